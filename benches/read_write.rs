@@ -16,7 +16,7 @@ fn initialization(c: &mut Criterion) {
     for n in [16, 32, 64, 128, 256, 512].iter() {
         group.bench_with_input(BenchmarkId::from_parameter(n), n, |b, &n| {
             b.iter(|| {
-                black_box(SqrtOram::new(n, BLOCK_SIZE));
+                black_box(SqrtOram::new(n, BLOCK_SIZE).unwrap());
             });
         });
     }
@@ -31,9 +31,9 @@ fn put_same_location(c: &mut Criterion) {
 
     for n in [16, 32, 64, 128, 256, 512, 1024, 2048, 4096].iter() {
         group.bench_with_input(BenchmarkId::from_parameter(n), n, |b, &n| {
-            let mut oram = SqrtOram::new(n, BLOCK_SIZE);
+            let mut oram = SqrtOram::new(n, BLOCK_SIZE).unwrap();
             b.iter(|| {
-                oram.put(0, vec![0; BLOCK_SIZE]);
+                oram.put(0, vec![0; BLOCK_SIZE]).unwrap();
             });
         });
     }
@@ -46,10 +46,10 @@ fn get_same_location(c: &mut Criterion) {
 
     for n in [16, 32, 64, 128, 256, 512, 1024, 2048, 4096].iter() {
         group.bench_with_input(BenchmarkId::from_parameter(n), n, |b, &n| {
-            let mut oram = SqrtOram::new(n, BLOCK_SIZE);
-            oram.put(0, vec![0; BLOCK_SIZE]);
+            let mut oram = SqrtOram::new(n, BLOCK_SIZE).unwrap();
+            oram.put(0, vec![0; BLOCK_SIZE]).unwrap();
             b.iter(|| {
-                black_box(oram.get(0));
+                black_box(oram.get(0).unwrap());
             });
         });
     }
@@ -62,10 +62,10 @@ fn put_random_location(c: &mut Criterion) {
 
     for n in [16, 32, 64, 128, 256, 512, 1024, 2048, 4096].iter() {
         group.bench_with_input(BenchmarkId::from_parameter(n), n, |b, &n| {
-            let mut oram = SqrtOram::new(n, BLOCK_SIZE);
-            let k = rand::thread_rng().gen_range(0, n) as u32;
+            let mut oram = SqrtOram::new(n, BLOCK_SIZE).unwrap();
+            let k = rand::thread_rng().gen_range(0, n);
             b.iter(|| {
-                oram.put(k, vec![0; BLOCK_SIZE]);
+                oram.put(k, vec![0; BLOCK_SIZE]).unwrap();
             });
         });
     }
@@ -78,11 +78,11 @@ fn get_random_location(c: &mut Criterion) {
 
     for n in [16, 32, 64, 128, 256, 512, 1024, 2048, 4096].iter() {
         group.bench_with_input(BenchmarkId::from_parameter(n), n, |b, &n| {
-            let mut oram = SqrtOram::new(n, BLOCK_SIZE);
-            let k = rand::thread_rng().gen_range(0, n) as u32;
-            oram.put(k, vec![0; BLOCK_SIZE]);
+            let mut oram = SqrtOram::new(n, BLOCK_SIZE).unwrap();
+            let k = rand::thread_rng().gen_range(0, n);
+            oram.put(k, vec![0; BLOCK_SIZE]).unwrap();
             b.iter(|| {
-                black_box(oram.get(k));
+                black_box(oram.get(k).unwrap());
             });
         });
     }
@@ -94,6 +94,9 @@ pub extern "C" fn say_something(some_string: *const u8, some_len: usize) -> sgx_
     println!("running oram - in memory");
     oram::example_in_memory();
 
+    println!("running oram - typed values");
+    oram::example_typed();
+
     println!("running oram - on disk");
     oram::example_on_disk(false);
 
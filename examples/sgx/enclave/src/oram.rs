@@ -12,36 +12,52 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use oram::SqrtOram;
+use oram::{SqrtOram, TypedOram};
+use std::string::{String, ToString};
 
 pub fn example_in_memory() {
-    let n = 16 as usize;
+    let n = 16 as u64;
     let block_size = 16 as usize;
-    let mut oram = SqrtOram::new(n, block_size);
+    let mut oram = SqrtOram::new(n, block_size).expect("create oram");
 
     for i in 0..n {
         let mut data = vec![0u8; block_size];
         data[0..8].copy_from_slice(&i.to_le_bytes());
-        oram.put(i as u32, data);
+        oram.put(i, data).expect("put data");
     }
 
     for i in 0..n {
         let mut data = vec![0u8; block_size];
         data[0..8].copy_from_slice(&i.to_le_bytes());
-        assert_eq!(&data[..], &oram.get(i as u32).unwrap()[..]);
+        assert_eq!(&data[..], &oram.get(i).expect("get data").unwrap()[..]);
+    }
+}
+
+pub fn example_typed() {
+    let n = 16 as u64;
+    let largest = (u64::MAX, "x".repeat(32));
+    let max_size = TypedOram::block_size_for(&largest).expect("compute block size");
+    let mut oram: TypedOram<(u64, String)> = TypedOram::new(n, max_size).expect("create oram");
+
+    for i in 0..n {
+        oram.put(i, &(i, i.to_string())).expect("put value");
+    }
+
+    for i in 0..n {
+        assert_eq!(oram.get(i).expect("get value"), Some((i, i.to_string())));
     }
 }
 
 pub fn example_on_disk(get_only: bool) {
-    let n = 64 as usize;
+    let n = 64 as u64;
     let block_size = 512 as usize;
-    let mut oram = SqrtOram::open("db", n, block_size);
+    let mut oram = SqrtOram::open("db", n, block_size).expect("open oram");
 
     if !get_only {
         for i in 0..n {
             let mut data = vec![0u8; block_size];
             data[0..8].copy_from_slice(&i.to_le_bytes());
-            oram.put(i as u32, data);
+            oram.put(i, data).expect("put data");
             println!("ENCLAVE put data {}", i);
         }
     }
@@ -49,7 +65,9 @@ pub fn example_on_disk(get_only: bool) {
     for i in 0..n {
         let mut data = vec![0u8; block_size];
         data[0..8].copy_from_slice(&i.to_le_bytes());
-        assert_eq!(&data[..], &oram.get(i as u32).unwrap()[..]);
+        assert_eq!(&data[..], &oram.get(i).expect("get data").unwrap()[..]);
         println!("ENCLAVE get data {}", i);
     }
+
+    oram.close().expect("close oram");
 }
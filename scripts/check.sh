@@ -2,4 +2,5 @@
 set -e
 
 cargo check --lib
+cargo check --features=parallel --lib
 cargo check --no-default-features --features=sgx --lib
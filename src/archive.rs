@@ -0,0 +1,362 @@
+// Copyright 2020 ADVANCA PTE. LTD.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Portable archives of ORAM contents
+//!
+//! An archive does not depend on the backend, salt or layout of the exported
+//! store. It is laid out as:
+//!
+//! - a header: magic, version, `n`, block size and a random nonce prefix
+//! - the tag of the header, so that forged sizes are rejected before a store
+//!   is built
+//! - one record per key in key order: an occupancy byte, the data length as
+//!   u64 and the data padded to the block size, sealed with XChaCha20-Poly1305
+//!   under the nonce prefix and the key, with the header as associated data
+//!
+//! Records have a fixed size, so the archive only reveals `n` and the block size.
+//! The key in the nonce keeps records from being reordered, and the number of
+//! records is fixed by the header. An import reads the records twice: once to
+//! check them all, and once to store them. Nothing is stored from an archive
+//! that fails the first check.
+
+#[cfg(feature = "sgx")]
+use sgx_tstd::{self as std, prelude::v1::*};
+
+use crate::builder::{default_rng, Key};
+use crate::convert::TryInto;
+use crate::crypto::{cipher, NONCE_LEN, TAG_LEN};
+use crate::{Data, OramError, Result, SqrtOram};
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+const MAGIC: &[u8; 8] = b"ORAMARCH";
+const VERSION: u32 = 3;
+/// Length of the random part of the nonces, followed by the key of a record
+const PREFIX_LEN: usize = NONCE_LEN - 8;
+/// Nonce suffix of the header tag, never the key of a record
+const HEADER_INDEX: u64 = u64::MAX;
+
+/// Header of an archive
+pub(crate) struct Header {
+    pub n: u64,
+    pub block_size: usize,
+    prefix: [u8; PREFIX_LEN],
+}
+
+impl Header {
+    const LEN: usize = 8 + 4 + 8 + 8 + PREFIX_LEN;
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::LEN);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.n.to_le_bytes());
+        bytes.extend_from_slice(&(self.block_size as u64).to_le_bytes());
+        bytes.extend_from_slice(&self.prefix);
+        bytes
+    }
+
+    /// Read the header at the start of `input` and check its tag under `key`
+    pub fn read<R: Read>(input: &mut R, key: &Key) -> Result<Self> {
+        let mut bytes = [0u8; Self::LEN];
+        input.read_exact(&mut bytes).map_err(io_error)?;
+        if &bytes[0..8] != MAGIC {
+            return Err(OramError::Serialization("not an ORAM archive".into()));
+        }
+        let version = u32::from_le_bytes(bytes[8..12].try_into().expect("slice to array"));
+        if version != VERSION {
+            return Err(OramError::Serialization(
+                "unsupported archive version".into(),
+            ));
+        }
+        let n = u64::from_le_bytes(bytes[12..20].try_into().expect("slice to array"));
+        let block_size = u64::from_le_bytes(bytes[20..28].try_into().expect("slice to array"));
+        let header = Header {
+            n,
+            block_size: block_size as usize,
+            prefix: bytes[28..].try_into().expect("slice to array"),
+        };
+        let mut tag = [0u8; TAG_LEN];
+        input.read_exact(&mut tag).map_err(io_error)?;
+        Cipher::new(key, &header).open(HEADER_INDEX, &tag)?;
+        Ok(header)
+    }
+
+    fn record_len(&self) -> usize {
+        1 + 8 + self.block_size
+    }
+
+    fn sealed_record_len(&self) -> usize {
+        self.record_len() + TAG_LEN
+    }
+}
+
+/// Sealing of the header and records of one archive
+struct Cipher {
+    aead: XChaCha20Poly1305,
+    prefix: [u8; PREFIX_LEN],
+    /// Associated data of every record
+    header: Vec<u8>,
+}
+
+impl Cipher {
+    fn new(key: &Key, header: &Header) -> Self {
+        Cipher {
+            aead: cipher(key, b"oram archive"),
+            prefix: header.prefix,
+            header: header.to_bytes(),
+        }
+    }
+
+    fn nonce(&self, k: u64) -> XNonce {
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[..PREFIX_LEN].copy_from_slice(&self.prefix);
+        nonce[PREFIX_LEN..].copy_from_slice(&k.to_le_bytes());
+        XNonce::from(nonce)
+    }
+
+    fn seal(&self, k: u64, record: &[u8]) -> Vec<u8> {
+        let payload = Payload {
+            msg: record,
+            aad: &self.header,
+        };
+        self.aead
+            .encrypt(&self.nonce(k), payload)
+            .expect("record too long to encrypt")
+    }
+
+    fn open(&self, k: u64, sealed: &[u8]) -> Result<Vec<u8>> {
+        let payload = Payload {
+            msg: sealed,
+            aad: &self.header,
+        };
+        self.aead
+            .decrypt(&self.nonce(k), payload)
+            .map_err(|_| OramError::Integrity(None))
+    }
+}
+
+/// Check the records following `header` in `input`, then seek back to the
+/// first record
+pub(crate) fn check_records<R: Read + Seek>(
+    key: &Key,
+    header: &Header,
+    input: &mut R,
+) -> Result<()> {
+    let cipher = Cipher::new(key, header);
+    let mut sealed = vec![0u8; header.sealed_record_len()];
+    for k in 0..header.n {
+        input.read_exact(&mut sealed).map_err(io_error)?;
+        cipher.open(k, &sealed)?;
+    }
+    // all of it was read, so the length fits
+    let len = header.n * header.sealed_record_len() as u64;
+    input
+        .seek(SeekFrom::Current(-(len as i64)))
+        .map_err(io_error)?;
+    Ok(())
+}
+
+fn io_error(e: io::Error) -> OramError {
+    OramError::Storage(e.to_string())
+}
+
+fn encode_record(content: Option<Data>, record: &mut [u8]) {
+    for b in record.iter_mut() {
+        *b = 0;
+    }
+    if let Some(data) = content {
+        record[0] = 1;
+        record[1..9].copy_from_slice(&(data.len() as u64).to_le_bytes());
+        record[9..9 + data.len()].copy_from_slice(&data);
+    }
+}
+
+fn decode_record(record: &[u8]) -> Result<Option<Data>> {
+    let len = u64::from_le_bytes(record[1..9].try_into().expect("slice to array")) as usize;
+    match record[0] {
+        0 => Ok(None),
+        1 if len <= record.len() - 9 => Ok(Some(record[9..9 + len].to_vec())),
+        _ => Err(OramError::Integrity(None)),
+    }
+}
+
+impl SqrtOram {
+    /// Write every key to `out` as an archive encrypted and authenticated under `key`
+    ///
+    /// The blocks are read with an oblivious scan, see `iter()`. The archive can
+    /// be loaded into a new store on any backend with `OramBuilder::import()`.
+    pub fn export<W: Write>(&mut self, key: &Key, mut out: W) -> Result<()> {
+        let mut prefix = [0u8; PREFIX_LEN];
+        default_rng(&mut prefix);
+        let header = Header {
+            n: self.n,
+            block_size: self.block_size,
+            prefix,
+        };
+        let cipher = Cipher::new(key, &header);
+        out.write_all(&header.to_bytes()).map_err(io_error)?;
+        out.write_all(&cipher.seal(HEADER_INDEX, &[]))
+            .map_err(io_error)?;
+
+        let mut record = vec![0u8; header.record_len()];
+        for item in self.iter()? {
+            let (k, content) = item?;
+            encode_record(content, &mut record);
+            out.write_all(&cipher.seal(k, &record)).map_err(io_error)?;
+        }
+        self.finish_scan()
+    }
+
+    /// Fill a new ORAM with the records following `header` in `input`
+    ///
+    /// Records are written in place after rearranging the blocks, then the
+    /// blocks are shuffled once. Every record is opened again, in case `input`
+    /// changed since `check_records()`.
+    pub(crate) fn import<R: Read>(
+        &mut self,
+        key: &Key,
+        header: &Header,
+        mut input: R,
+    ) -> Result<()> {
+        if !self.scanning {
+            self.rearrange()?;
+            self.scanning = true;
+        }
+        let cipher = Cipher::new(key, header);
+        let mut sealed = vec![0u8; header.sealed_record_len()];
+        for k in 0..self.n {
+            input.read_exact(&mut sealed).map_err(io_error)?;
+            let content = decode_record(&cipher.open(k, &sealed)?)?;
+            let mut block = self.read_block(k)?;
+            self.apply(&mut block, |c| *c = content);
+            self.write_block(k, &block)?;
+        }
+        self.finish_scan()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{Backend, OramBuilder};
+    use std::io::Cursor;
+
+    const KEY: Key = [3; 32];
+    /// Offset of the first record
+    const RECORDS: usize = Header::LEN + TAG_LEN;
+    /// Length of a sealed record of a block of 16 bytes
+    const RECORD: usize = 1 + 8 + 16 + TAG_LEN;
+
+    fn exported() -> Vec<u8> {
+        let mut oram = SqrtOram::new(10, 16).unwrap();
+        for i in (0..10).filter(|i| i % 2 == 0) {
+            oram.put(i, vec![i as u8; i as usize]).unwrap();
+        }
+        let mut archive = Vec::new();
+        oram.export(&KEY, &mut archive).unwrap();
+        archive
+    }
+
+    #[test]
+    fn export_and_import() {
+        let archive = exported();
+        assert_eq!(archive.len(), RECORDS + 10 * RECORD);
+
+        // n and block size are taken from the archive
+        let mut oram = OramBuilder::new(1, 1)
+            .encryption_key([1; 32])
+            .import(&KEY, Cursor::new(&archive))
+            .unwrap();
+        assert_eq!((oram.num_blocks(), oram.block_size()), (10, 16));
+        assert_eq!(oram.len(), 5);
+        for i in 0..10 {
+            let expected = if i % 2 == 0 {
+                Some(vec![i as u8; i as usize])
+            } else {
+                None
+            };
+            assert_eq!(oram.get(i).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn archive_hides_contents() {
+        let archive = exported();
+        // key 8 holds eight bytes of 8
+        let record = &archive[RECORDS + 8 * RECORD..RECORDS + 9 * RECORD];
+        assert_ne!(&record[9..17], &[8; 8]);
+    }
+
+    #[test]
+    fn reject_tampered_archive() {
+        let import =
+            |key: &Key, archive: &[u8]| OramBuilder::new(1, 1).import(key, Cursor::new(archive));
+        let mut archive = exported();
+        archive[RECORDS + 30] ^= 1;
+        let result = import(&KEY, &archive);
+        assert!(matches!(result, Err(OramError::Integrity(None))));
+
+        // a forged size is rejected before anything is allocated
+        let mut archive = exported();
+        archive[19] = 0xff;
+        let result = import(&KEY, &archive);
+        assert!(matches!(result, Err(OramError::Integrity(None))));
+
+        // records cannot be reordered
+        let mut archive = exported();
+        let (first, second) = archive[RECORDS..RECORDS + 2 * RECORD].split_at_mut(RECORD);
+        first.swap_with_slice(second);
+        let result = import(&KEY, &archive);
+        assert!(matches!(result, Err(OramError::Integrity(None))));
+
+        let archive = exported();
+        let result = import(&[4; 32], &archive);
+        assert!(matches!(result, Err(OramError::Integrity(None))));
+
+        let result = import(&KEY, &archive[..archive.len() - 1]);
+        assert!(matches!(result, Err(OramError::Storage(_))));
+
+        let result = import(&KEY, &[0u8; 64]);
+        assert!(matches!(result, Err(OramError::Serialization(_))));
+    }
+
+    #[test]
+    fn reject_tampered_archive_into_persistent_store() {
+        let path = std::env::temp_dir().join("oram_import");
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_dir_all(path);
+        let import = |archive: &[u8]| {
+            OramBuilder::new(1, 1)
+                .backend(Backend::LevelDb)
+                .path(path)
+                .import(&KEY, Cursor::new(archive))
+        };
+
+        let mut archive = exported();
+        archive[RECORDS + 30] ^= 1;
+        assert!(matches!(import(&archive), Err(OramError::Integrity(None))));
+        assert!(!std::path::Path::new(path).exists());
+
+        let mut oram = import(&exported()).unwrap();
+        assert_eq!(oram.get(4).unwrap(), Some(vec![4; 4]));
+        drop(oram);
+
+        // a store that existed before is kept
+        assert_eq!(import(&exported()).err(), Some(OramError::AlreadyExists));
+        assert!(std::path::Path::new(path).exists());
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -0,0 +1,557 @@
+// Copyright 2020 ADVANCA PTE. LTD.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::archive;
+use crate::convert::TryInto;
+use crate::db::{self, Database, SealOptions};
+use crate::format;
+use crate::fs;
+use crate::io::{Read, Seek};
+use crate::sort::Network;
+use crate::string::String;
+use crate::trace::Recorder;
+use crate::{Input, OramError, Result, SqrtOram, Tuning, VarBlake2b, VariableOutput};
+
+/// Salt of the PRF, rotated on every reshuffle
+pub type Salt = [u8; 32];
+
+/// Secret key used for block encryption and integrity
+pub type Key = [u8; 32];
+
+/// Pseudo-random function deriving the tag of a block from its position
+pub type PrfFn = fn(&Salt, u64) -> u64;
+
+/// Source of randomness filling the given buffer
+pub type RngFn = fn(&mut [u8]);
+
+/// Storage backend of an ORAM
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// No persistence, for testing only. Available in std and sgx.
+    InMemory,
+    /// LevelDB. Only available in std.
+    LevelDb,
+    /// SGX protected file system. Only available in sgx.
+    SgxFs,
+}
+
+impl Backend {
+    /// The persistent backend of the current feature set
+    pub fn persistent() -> Self {
+        if cfg!(feature = "sgx") {
+            Backend::SgxFs
+        } else {
+            Backend::LevelDb
+        }
+    }
+}
+
+/// What `OramBuilder::build` does about an existing store
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpenMode {
+    /// Create a new store. Fails if it already exists.
+    Create,
+    /// Open an existing store. Fails if it does not exist.
+    Open,
+    /// Open an existing store or create a new one
+    OpenOrCreate,
+}
+
+/// How `SqrtOram` shuffles its blocks at the start of every epoch
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShuffleMode {
+    /// Tag the blocks with the PRF and sort them by tag with the sorting network,
+    /// in O(n log^2 n) block accesses
+    SortByTag,
+    /// Apply a random permutation with `sort::MelbourneShuffle`, in O(n) block
+    /// accesses. It needs storage for about three more blocks per block.
+    Melbourne,
+}
+
+/// How `SqrtOram` finds the position of a block in trusted memory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PositionLookup {
+    /// Read the position table at the index of the block, in constant time.
+    /// The address read reveals the index to an attacker observing the
+    /// enclave's memory accesses, through page faults or the cache.
+    Direct,
+    /// Scan the whole position table with constant-time selects, in time
+    /// linear in the number of blocks, touching the same addresses for
+    /// every block. The table is built with a sorting network at each epoch.
+    Oblivious,
+}
+
+/// Keyed BLAKE2b, the default PRF
+pub fn default_prf(salt: &Salt, index: u64) -> u64 {
+    let mut hasher = VarBlake2b::new_keyed(salt, 8);
+    hasher.input(index.to_be_bytes());
+    let hash = hasher.vec_result();
+    u64::from_be_bytes(hash[0..8].try_into().expect("slice to array"))
+}
+
+/// The thread-local RNG of the current feature set, the default RNG
+#[cfg(feature = "std")]
+pub fn default_rng(buf: &mut [u8]) {
+    use rand_core::RngCore;
+    rand::thread_rng().fill_bytes(buf)
+}
+
+/// The thread-local RNG of the current feature set, the default RNG
+#[cfg(feature = "sgx")]
+pub fn default_rng(buf: &mut [u8]) {
+    use sgx_rand::Rng;
+    sgx_rand::thread_rng().fill_bytes(buf)
+}
+
+/// The default shelter size for `n` real blocks, `sqrt(n)`
+pub(crate) fn default_shelter_size(n: u64) -> u64 {
+    (n as f64).sqrt() as u64
+}
+
+/// Builder of `SqrtOram`
+///
+/// # Examples
+///
+/// ```
+/// use oram::{Backend, OramBuilder};
+///
+/// let mut oram = OramBuilder::new(16, 32)
+///     .backend(Backend::InMemory)
+///     .shelter_size(2)
+///     .encryption_key([0; 32])
+///     .build()
+///     .unwrap();
+/// oram.put(1, vec![1, 2, 3]).unwrap();
+/// ```
+#[derive(Clone)]
+pub struct OramBuilder {
+    n: u64,
+    block_size: usize,
+    backend: Backend,
+    path: Option<String>,
+    shelter_size: Option<u64>,
+    prf: PrfFn,
+    rng: RngFn,
+    key: Option<Key>,
+    encryption: bool,
+    integrity: bool,
+    mode: OpenMode,
+    batch_padding: u64,
+    network: Network,
+    shuffle_mode: ShuffleMode,
+    sort_memory: Option<u64>,
+    lookup: PositionLookup,
+    trace: Option<Recorder>,
+}
+
+impl OramBuilder {
+    /// Start configuring an in-memory ORAM
+    ///
+    /// - `n`: number of real blocks
+    /// - `block_size`: size of each blocks in bytes
+    pub fn new(n: u64, block_size: usize) -> Self {
+        OramBuilder {
+            n,
+            block_size,
+            backend: Backend::InMemory,
+            path: None,
+            shelter_size: None,
+            prf: default_prf,
+            rng: default_rng,
+            key: None,
+            encryption: false,
+            integrity: false,
+            mode: OpenMode::OpenOrCreate,
+            batch_padding: 1,
+            network: Network::OddEvenMerge,
+            shuffle_mode: ShuffleMode::SortByTag,
+            sort_memory: None,
+            lookup: PositionLookup::Direct,
+            trace: None,
+        }
+    }
+
+    /// Storage backend. Defaults to `Backend::InMemory`.
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Location of a persistent store; the data directory on file system
+    pub fn path<P: Into<String>>(mut self, path: P) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    /// Number of blocks in shelter. Defaults to `sqrt(n)`.
+    ///
+    /// A larger shelter makes each access slower but reshuffles less often.
+    pub fn shelter_size(mut self, shelter_size: u64) -> Self {
+        self.shelter_size = Some(shelter_size);
+        self
+    }
+
+    /// PRF deriving block tags for `ShuffleMode::SortByTag`. Defaults to `default_prf`.
+    pub fn prf(mut self, prf: PrfFn) -> Self {
+        self.prf = prf;
+        self
+    }
+
+    /// Source of salts and dummy data. Defaults to `default_rng`.
+    ///
+    /// Nonces sealing values always come from `default_rng`, whatever is set here.
+    pub fn rng(mut self, rng: RngFn) -> Self {
+        self.rng = rng;
+        self
+    }
+
+    /// Encrypt blocks with XChaCha20-Poly1305 under a key derived from `key`.
+    /// Integrity is enabled as well.
+    pub fn encryption_key(mut self, key: Key) -> Self {
+        self.key = Some(key);
+        self.encryption = true;
+        self.integrity = true;
+        self
+    }
+
+    /// Whether blocks are encrypted, which also authenticates them. Requires a key.
+    pub fn encryption(mut self, encryption: bool) -> Self {
+        self.encryption = encryption;
+        self
+    }
+
+    /// Whether blocks are authenticated with a MAC. Requires a key.
+    ///
+    /// This detects forged or moved values but not rollback: a value sealed
+    /// earlier under the same key still opens.
+    pub fn integrity(mut self, integrity: bool) -> Self {
+        self.integrity = integrity;
+        self
+    }
+
+    /// What to do about an existing store. Defaults to `OpenMode::OpenOrCreate`.
+    pub fn mode(mut self, mode: OpenMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Round the number of blocks fetched by `get_many()` and `put_many()` up to
+    /// a multiple of `batch_padding`, hiding the exact batch size. Defaults to 1.
+    pub fn batch_padding(mut self, batch_padding: u64) -> Self {
+        self.batch_padding = batch_padding;
+        self
+    }
+
+    /// Sorting network used to rearrange the blocks, and to shuffle them with
+    /// `ShuffleMode::SortByTag`. Defaults to `Network::OddEvenMerge`, which
+    /// needs the fewest block accesses.
+    pub fn sorting_network(mut self, network: Network) -> Self {
+        self.network = network;
+        self
+    }
+
+    /// How the blocks are shuffled for every epoch. Defaults to `ShuffleMode::SortByTag`.
+    pub fn shuffle_mode(mut self, shuffle_mode: ShuffleMode) -> Self {
+        self.shuffle_mode = shuffle_mode;
+        self
+    }
+
+    /// How blocks are located in trusted memory. Defaults to `PositionLookup::Direct`.
+    pub fn position_lookup(mut self, lookup: PositionLookup) -> Self {
+        self.lookup = lookup;
+        self
+    }
+
+    /// Sort with up to `blocks` blocks in trusted memory, see `sort::sort_in_chunks()`
+    ///
+    /// This takes far fewer block accesses than sorting one comparator at a
+    /// time, which is the default, and takes precedence over sorting in
+    /// parallel. At least two blocks are needed.
+    pub fn sort_memory(mut self, blocks: u64) -> Self {
+        self.sort_memory = Some(blocks);
+        self
+    }
+
+    /// Record every access reaching the backend into `recorder`, see `trace`
+    pub fn trace(mut self, recorder: &Recorder) -> Self {
+        self.trace = Some(recorder.clone());
+        self
+    }
+
+    /// Validate the options and build the ORAM
+    ///
+    /// # Errors
+    ///
+    /// - `OramError::InvalidConfig` for an invalid combination of options
+    /// - `OramError::NotFound` or `OramError::AlreadyExists` if the store
+    ///   does not match `mode`
+    /// - `OramError::UnsupportedFormat` if the store has another format
+    ///   version, see `migrate()`
+    /// - other errors when the store cannot be opened
+    pub fn build(self) -> Result<SqrtOram> {
+        self.validate()?;
+        let db = self.open_db()?;
+        if db.existed() && self.mode == OpenMode::Create {
+            return Err(OramError::AlreadyExists);
+        }
+        self.create(db)
+    }
+
+    fn create(self, db: Database) -> Result<SqrtOram> {
+        let shelter_size = self.resolved_shelter_size();
+        SqrtOram::create(
+            self.n,
+            shelter_size,
+            self.block_size,
+            db,
+            self.prf,
+            self.rng,
+            Tuning {
+                batch_padding: self.batch_padding,
+                network: self.network,
+                shuffle: self.shuffle_mode,
+                sort_memory: self.sort_memory,
+                lookup: self.lookup,
+            },
+        )
+    }
+
+    /// Build a new ORAM holding the contents of an archive made by `SqrtOram::export()`
+    ///
+    /// The number of blocks and the block size are taken from the archive; the
+    /// values given to `new()` are ignored.
+    ///
+    /// The whole archive is authenticated before the store is created. If
+    /// creating the store or storing the records fails anyway, the new store
+    /// is removed. A store that existed before is left alone.
+    ///
+    /// # Errors
+    ///
+    /// - `OramError::InvalidConfig` if `mode` is `OpenMode::Open`
+    /// - `OramError::AlreadyExists` if the store exists
+    /// - `OramError::Integrity` if the archive was modified or `key` is wrong
+    /// - `OramError::Serialization` if `input` is not an archive
+    /// - besides the errors of `build()`
+    pub fn import<R: Read + Seek>(mut self, key: &Key, mut input: R) -> Result<SqrtOram> {
+        if self.mode == OpenMode::Open {
+            return Err(OramError::InvalidConfig(
+                "an archive is imported into a new store".into(),
+            ));
+        }
+        let header = archive::Header::read(&mut input, key)?;
+        archive::check_records(key, &header, &mut input)?;
+        self.n = header.n;
+        self.block_size = header.block_size;
+        self.mode = OpenMode::Create;
+        let existed = self.store_exists();
+        // the ORAM is dropped before its files are removed
+        let result = self
+            .clone()
+            .build()
+            .and_then(|mut oram| oram.import(key, &header, input).map(|()| oram));
+        if result.is_err() && !existed && self.store_exists() {
+            self.remove_store()?;
+        }
+        result
+    }
+
+    /// Whether the files of a persistent store exist
+    fn store_exists(&self) -> bool {
+        match (self.backend, &self.path) {
+            (Backend::InMemory, _) | (_, None) => false,
+            (_, Some(path)) => fs::metadata(path).is_ok(),
+        }
+    }
+
+    /// Remove the files of a persistent store
+    fn remove_store(&self) -> Result<()> {
+        match (self.backend, &self.path) {
+            (Backend::InMemory, _) | (_, None) => Ok(()),
+            (_, Some(path)) => {
+                fs::remove_dir_all(path).map_err(|e| OramError::Storage(e.to_string()))
+            }
+        }
+    }
+
+    /// Open an existing store, converting it to the current format first if needed
+    ///
+    /// A store of an older format is rewritten block by block in the order of
+    /// positions, then shuffled like any reopened store, so the migration does
+    /// not reveal its contents. An interrupted migration can be run again.
+    ///
+    /// # Errors
+    ///
+    /// - `OramError::NotFound` if the store does not exist
+    /// - `OramError::UnsupportedFormat` if the store is newer than this crate
+    /// - besides the errors of `build()`
+    pub fn migrate(mut self) -> Result<SqrtOram> {
+        self.mode = OpenMode::Open;
+        self.validate()?;
+        let mut db = self.open_db()?;
+        format::migrate(&mut db, self.n, self.block_size)?;
+        self.create(db)
+    }
+
+    fn resolved_shelter_size(&self) -> u64 {
+        self.shelter_size
+            .unwrap_or_else(|| default_shelter_size(self.n))
+    }
+
+    fn validate(&self) -> Result<()> {
+        let invalid = |msg: &str| Err(OramError::InvalidConfig(msg.into()));
+
+        if self.n == 0 {
+            return invalid("the number of blocks must be positive");
+        }
+        let shelter_size = self.resolved_shelter_size();
+        if shelter_size == 0 || shelter_size > self.n {
+            return invalid("the shelter size must be in 1..=n");
+        }
+        if self.batch_padding == 0 {
+            return invalid("the batch padding must be positive");
+        }
+        if let Some(0..=1) = self.sort_memory {
+            return invalid("the sort memory must hold at least two blocks");
+        }
+        match (self.backend, &self.path) {
+            (Backend::InMemory, Some(_)) => return invalid("in-memory backend takes no path"),
+            (Backend::InMemory, None) if self.mode == OpenMode::Open => {
+                return invalid("in-memory backend has no existing store")
+            }
+            (Backend::LevelDb, _) | (Backend::SgxFs, _)
+                if self.backend != Backend::persistent() =>
+            {
+                return invalid("backend is not available with the current features")
+            }
+            (Backend::LevelDb, None) | (Backend::SgxFs, None) => {
+                return invalid("persistent backend requires a path")
+            }
+            _ => {}
+        }
+        if (self.encryption || self.integrity) && self.key.is_none() {
+            return invalid("encryption and integrity require a key");
+        }
+        Ok(())
+    }
+
+    fn open_db(&self) -> Result<Database> {
+        let opt = match self.backend {
+            Backend::InMemory => db::Options::in_memory(),
+            #[cfg(feature = "std")]
+            Backend::LevelDb => db::Options::leveldb(),
+            #[cfg(feature = "sgx")]
+            Backend::SgxFs => db::Options::sgxfs(),
+            _ => unreachable!("checked by validate()"),
+        };
+        let seal = match self.key {
+            Some(key) if self.encryption || self.integrity => Some(SealOptions {
+                key,
+                encryption: self.encryption,
+                integrity: self.integrity,
+            }),
+            _ => None,
+        };
+        let opt = opt
+            .create_if_missing(self.mode != OpenMode::Open)
+            .seal(seal)
+            .trace(self.trace.clone());
+        Database::open(self.path.as_deref().unwrap_or("in-memory"), opt)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    fn invalid(builder: OramBuilder) -> bool {
+        matches!(builder.build(), Err(OramError::InvalidConfig(_)))
+    }
+
+    #[test]
+    fn reject_invalid_config() {
+        assert!(invalid(OramBuilder::new(0, 16)));
+        assert!(invalid(OramBuilder::new(16, 16).shelter_size(0)));
+        assert!(invalid(OramBuilder::new(16, 16).shelter_size(17)));
+        assert!(invalid(OramBuilder::new(16, 16).batch_padding(0)));
+        assert!(invalid(OramBuilder::new(16, 16).sort_memory(1)));
+        assert!(invalid(OramBuilder::new(16, 16).path("db")));
+        assert!(invalid(OramBuilder::new(16, 16).mode(OpenMode::Open)));
+        assert!(invalid(OramBuilder::new(16, 16).backend(Backend::LevelDb)));
+        assert!(invalid(
+            OramBuilder::new(16, 16).backend(Backend::SgxFs).path("db")
+        ));
+        assert!(invalid(OramBuilder::new(16, 16).integrity(true)));
+        assert!(invalid(OramBuilder::new(16, 16).encryption(true)));
+    }
+
+    #[test]
+    fn custom_shelter_size() {
+        let n = 16;
+        let mut oram = OramBuilder::new(n, 16).shelter_size(6).build().unwrap();
+        for i in 0..n {
+            oram.put(i, vec![i as u8]).unwrap();
+        }
+        for i in 0..n {
+            assert_eq!(oram.get(i).unwrap().unwrap()[0], i as u8);
+        }
+    }
+
+    fn counting_rng(buf: &mut [u8]) {
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+    }
+
+    fn identity_prf(salt: &Salt, index: u64) -> u64 {
+        index ^ u64::from(salt[0])
+    }
+
+    #[test]
+    fn custom_prf_and_rng() {
+        let mut oram = OramBuilder::new(9, 16)
+            .prf(identity_prf)
+            .rng(counting_rng)
+            .shuffle_mode(ShuffleMode::SortByTag)
+            .build()
+            .unwrap();
+        for i in 0..9 {
+            oram.put(i, vec![i as u8; 16]).unwrap();
+            assert_eq!(oram.get(i).unwrap().unwrap(), vec![i as u8; 16]);
+        }
+    }
+
+    #[test]
+    fn encrypted_round_trip() {
+        let mut oram = OramBuilder::new(9, 16)
+            .encryption_key([1; 32])
+            .build()
+            .unwrap();
+        for i in 0..9 {
+            oram.put(i, vec![i as u8; 16]).unwrap();
+        }
+        for i in 0..9 {
+            assert_eq!(oram.get(i).unwrap().unwrap(), vec![i as u8; 16]);
+        }
+    }
+
+    #[test]
+    fn require_existing_store() {
+        let result = OramBuilder::new(16, 16)
+            .backend(Backend::LevelDb)
+            .path("builder_require_existing_store")
+            .mode(OpenMode::Open)
+            .build();
+        assert_eq!(result.err(), Some(OramError::NotFound));
+    }
+}
@@ -0,0 +1,60 @@
+// Copyright 2020 ADVANCA PTE. LTD.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Primitives shared by sealed storage, archives and traces
+//!
+//! Keys are derived and values digested with BLAKE2b, and values are sealed
+//! with XChaCha20-Poly1305.
+
+use crate::builder::Key;
+use crate::convert::TryInto;
+use crate::{Input, VarBlake2b, VariableOutput};
+use chacha20poly1305::aead::NewAead;
+use chacha20poly1305::XChaCha20Poly1305;
+
+/// Length of an XChaCha20-Poly1305 nonce
+pub const NONCE_LEN: usize = 24;
+/// Length of an XChaCha20-Poly1305 tag
+pub const TAG_LEN: usize = 16;
+
+/// Derive an independent key for the purpose named by `label`
+pub fn derive_key(key: &Key, label: &[u8]) -> Key {
+    let mut hasher = VarBlake2b::new_keyed(key, 32);
+    hasher.input(label);
+    let mut derived = [0u8; 32];
+    derived.copy_from_slice(&hasher.vec_result());
+    derived
+}
+
+/// The 8-byte BLAKE2b hash of `data`, unkeyed, as a number
+pub fn short_hash(data: &[u8]) -> u64 {
+    let mut hasher = VarBlake2b::new_keyed(&[], 8);
+    hasher.input(data);
+    let hash = hasher.vec_result();
+    u64::from_be_bytes(hash[..].try_into().expect("slice to array"))
+}
+
+/// The cipher under a key derived from `key` for the purpose named by `label`
+pub fn cipher(key: &Key, label: &[u8]) -> XChaCha20Poly1305 {
+    XChaCha20Poly1305::new(&derive_key(key, label).into())
+}
+
+/// Compare two MACs in time independent of their content
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b.iter())
+            .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
@@ -0,0 +1,244 @@
+// Copyright 2020 ADVANCA PTE. LTD.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Constant-time selection, so that comparators do not branch on secret data
+//!
+//! The design follows the `subtle` crate: a `Choice` holds a secret bit and is
+//! only turned into masks, never into a `bool` that code could branch on.
+
+#[cfg(feature = "sgx")]
+use sgx_tstd as std;
+
+use std::mem;
+use std::ops::{BitAnd, BitOr, Not};
+use std::ptr;
+
+/// A secret boolean, either 0 or 1
+#[derive(Clone, Copy, Debug)]
+pub struct Choice(u8);
+
+impl Choice {
+    /// The bit as 0 or 1
+    ///
+    /// Branching on it leaks the choice, so it is meant for results that are
+    /// no longer secret.
+    pub fn unwrap_u8(self) -> u8 {
+        self.0
+    }
+
+    /// All ones if the choice is set, all zeros otherwise
+    fn mask(self) -> u64 {
+        0u64.wrapping_sub(u64::from(black_box(self.0)))
+    }
+}
+
+impl From<bool> for Choice {
+    fn from(b: bool) -> Self {
+        Choice(b as u8)
+    }
+}
+
+/// Takes the low bit of `b`, which should be 0 or 1
+impl From<u8> for Choice {
+    fn from(b: u8) -> Self {
+        debug_assert!(b <= 1, "a choice is 0 or 1");
+        Choice(b & 1)
+    }
+}
+
+impl BitAnd for Choice {
+    type Output = Choice;
+
+    fn bitand(self, rhs: Choice) -> Choice {
+        Choice(self.0 & rhs.0)
+    }
+}
+
+impl BitOr for Choice {
+    type Output = Choice;
+
+    fn bitor(self, rhs: Choice) -> Choice {
+        Choice(self.0 | rhs.0)
+    }
+}
+
+impl Not for Choice {
+    type Output = Choice;
+
+    fn not(self) -> Choice {
+        Choice(self.0 ^ 1)
+    }
+}
+
+/// Hide the value from the optimizer, which could otherwise turn masking back
+/// into branches
+#[inline(never)]
+fn black_box(x: u8) -> u8 {
+    // SAFETY: reading a local through a reference is always valid
+    unsafe { ptr::read_volatile(&x) }
+}
+
+/// Whether `a == b`, computed without branches
+pub fn ct_eq(a: u64, b: u64) -> Choice {
+    let x = a ^ b;
+    // the top bit of `x | -x` is set unless `x` is zero
+    Choice((((x | x.wrapping_neg()) >> 63) ^ 1) as u8)
+}
+
+/// Whether `a < b`, computed without branches
+pub fn ct_less(a: u64, b: u64) -> Choice {
+    // the borrow out of the top bit of `a - b`
+    let borrow = (!a & b) | (!(a ^ b) & a.wrapping_sub(b));
+    Choice((borrow >> 63) as u8)
+}
+
+/// Types whose values can be selected in time independent of the choice
+pub trait ConditionallySelectable: Sized {
+    /// `b` if `choice` is set, `a` otherwise
+    fn conditional_select(a: &Self, b: &Self, choice: Choice) -> Self;
+
+    /// Assign `other` to `self` if `choice` is set
+    fn conditional_assign(&mut self, other: &Self, choice: Choice) {
+        *self = Self::conditional_select(self, other, choice);
+    }
+
+    /// Swap `a` and `b` if `choice` is set
+    fn conditional_swap(a: &mut Self, b: &mut Self, choice: Choice) {
+        let x = Self::conditional_select(a, b, choice);
+        *b = Self::conditional_select(b, a, choice);
+        *a = x;
+    }
+}
+
+macro_rules! impl_integer {
+    ($($t:ty),*) => {$(
+        impl ConditionallySelectable for $t {
+            fn conditional_select(a: &Self, b: &Self, choice: Choice) -> Self {
+                let mask = choice.mask() as $t;
+                a ^ (mask & (a ^ b))
+            }
+        }
+    )*};
+}
+
+impl_integer!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+impl ConditionallySelectable for bool {
+    fn conditional_select(a: &Self, b: &Self, choice: Choice) -> Self {
+        u8::conditional_select(&(*a as u8), &(*b as u8), choice) != 0
+    }
+}
+
+impl<A, B> ConditionallySelectable for (A, B)
+where
+    A: ConditionallySelectable,
+    B: ConditionallySelectable,
+{
+    fn conditional_select(a: &Self, b: &Self, choice: Choice) -> Self {
+        (
+            A::conditional_select(&a.0, &b.0, choice),
+            B::conditional_select(&a.1, &b.1, choice),
+        )
+    }
+}
+
+impl<A, B, C> ConditionallySelectable for (A, B, C)
+where
+    A: ConditionallySelectable,
+    B: ConditionallySelectable,
+    C: ConditionallySelectable,
+{
+    fn conditional_select(a: &Self, b: &Self, choice: Choice) -> Self {
+        (
+            A::conditional_select(&a.0, &b.0, choice),
+            B::conditional_select(&a.1, &b.1, choice),
+            C::conditional_select(&a.2, &b.2, choice),
+        )
+    }
+}
+
+/// Pick `(a, b)` or, if `choice` is set, `(b, a)` by selecting their addresses,
+/// for types that are not `ConditionallySelectable`
+pub(crate) fn select_pair<'a, T>(a: &'a T, b: &'a T, choice: Choice) -> (&'a T, &'a T) {
+    let (x, y) = (a as *const T as usize, b as *const T as usize);
+    let first = usize::conditional_select(&x, &y, choice);
+    // SAFETY: both addresses are those of `a` and `b`, borrowed for `'a`
+    unsafe { (&*(first as *const T), &*((x ^ y ^ first) as *const T)) }
+}
+
+/// `b` if `choice` is set, `a` otherwise, by selecting their addresses
+fn select_mut<'a, T>(a: &'a mut T, b: &'a mut T, choice: Choice) -> &'a mut T {
+    let (x, y) = (a as *mut T as usize, b as *mut T as usize);
+    // SAFETY: the address is that of `a` or `b`, borrowed mutably for `'a`,
+    // and the other one is not used while the result lives
+    unsafe { &mut *(usize::conditional_select(&x, &y, choice) as *mut T) }
+}
+
+/// `Some(value)` if `choice` is set, `None` otherwise, without branching
+pub(crate) fn some_if<T>(value: T, choice: Choice) -> Option<T> {
+    let (mut none, mut some) = (None, Some(value));
+    mem::take(select_mut(&mut none, &mut some, choice))
+}
+
+/// The value in `option` if `choice` is set, the default otherwise, without
+/// branching on `choice`. `option` holds a value whenever `choice` is set.
+pub(crate) fn unwrap_if<T: Default>(mut option: Option<T>, choice: Choice) -> T {
+    let mut fallback = Some(T::default());
+    // holds a value either way, so unwrapping does not depend on `choice`
+    mem::take(select_mut(&mut fallback, &mut option, choice)).unwrap_or_default()
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn select_and_swap() {
+        let yes = Choice::from(true);
+        let no = Choice::from(false);
+        assert_eq!(u64::conditional_select(&1, &2, no), 1);
+        assert_eq!(u64::conditional_select(&1, &2, yes), 2);
+        assert_eq!(i8::conditional_select(&-1, &5, yes), 5);
+        assert!(bool::conditional_select(&false, &true, yes));
+        assert_eq!(
+            <(u8, u16)>::conditional_select(&(1, 2), &(3, 4), !no),
+            (3, 4)
+        );
+
+        let (mut a, mut b) = ((1u8, 2u64), (3u8, 4u64));
+        <(u8, u64)>::conditional_swap(&mut a, &mut b, no);
+        assert_eq!((a, b), ((1, 2), (3, 4)));
+        <(u8, u64)>::conditional_swap(&mut a, &mut b, yes);
+        assert_eq!((a, b), ((3, 4), (1, 2)));
+
+        let (x, y) = (1, 2);
+        assert_eq!(select_pair(&x, &y, no), (&1, &2));
+        assert_eq!(select_pair(&x, &y, yes), (&2, &1));
+        assert_eq!(some_if(vec![1], yes), Some(vec![1]));
+        assert_eq!(some_if(vec![1], no), None);
+        assert_eq!(unwrap_if(Some(vec![1]), yes), vec![1]);
+        assert_eq!(unwrap_if(None::<Vec<u8>>, no), vec![]);
+    }
+
+    #[test]
+    fn compare() {
+        let values = [0, 1, 2, 1 << 63, (1 << 63) + 1, u64::MAX - 1, u64::MAX];
+        for &a in &values {
+            for &b in &values {
+                assert_eq!(ct_less(a, b).unwrap_u8() == 1, a < b, "{} < {}", a, b);
+                assert_eq!(ct_eq(a, b).unwrap_u8() == 1, a == b, "{} == {}", a, b);
+            }
+        }
+    }
+}
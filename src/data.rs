@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::ct::{ct_less, Choice, ConditionallySelectable};
 use crate::fmt;
 use crate::{
     de, Deserialize, Deserializer, SeqAccess, Serialize, SerializeTuple, Serializer, Visitor,
@@ -33,6 +34,38 @@ pub struct DataWrapper {
     pub max_len: usize,
 }
 
+impl DataWrapper {
+    /// `buf` padded to `len` bytes, `len` bytes written whatever its length
+    fn padded(&self, len: usize) -> Data {
+        let mut padded = self.buf.clone();
+        padded.resize(len, PADDING_VALUE);
+        padded
+    }
+}
+
+/// Both buffers are padded to the larger `max_len`, the block size, then
+/// selected byte by byte with masks over that fixed length, and the length is
+/// selected with masks too.
+impl ConditionallySelectable for DataWrapper {
+    fn conditional_select(a: &Self, b: &Self, choice: Choice) -> Self {
+        let len = a.max_len.max(b.max_len);
+        let mut buf = a.padded(len);
+        for (x, y) in buf.iter_mut().zip(b.padded(len)) {
+            x.conditional_assign(&y, choice);
+        }
+        let selected = usize::conditional_select(&a.buf.len(), &b.buf.len(), choice);
+        // a buffer longer than its `max_len` is cut to `len`
+        let selected =
+            usize::conditional_select(&selected, &len, ct_less(len as u64, selected as u64));
+        // SAFETY: `selected <= len == buf.len()`, so the bytes are initialized
+        unsafe { buf.set_len(selected) };
+        DataWrapper {
+            buf,
+            max_len: usize::conditional_select(&a.max_len, &b.max_len, choice),
+        }
+    }
+}
+
 impl Serialize for DataWrapper {
     /// Serialize function
     ///
@@ -41,7 +74,7 @@ impl Serialize for DataWrapper {
     where
         S: Serializer,
     {
-        let padding = vec![PADDING_VALUE as u8; self.max_len - self.buf.len()];
+        let padding = vec![PADDING_VALUE; self.max_len - self.buf.len()];
         let mut s = serializer.serialize_tuple(2)?;
         s.serialize_element(&self.buf)?;
         s.serialize_element(&padding)?;
@@ -95,6 +128,7 @@ impl<'de> Deserialize<'de> for DataWrapper {
 #[cfg(all(test, feature = "std"))]
 mod tests {
     use super::{DataWrapper, PADDING_VALUE};
+    use crate::ct::{Choice, ConditionallySelectable};
     use serde_test::{assert_de_tokens, assert_ser_tokens, Token};
 
     #[test]
@@ -240,4 +274,34 @@ mod tests {
             ],
         );
     }
+
+    #[test]
+    fn test_select_keeps_lengths() {
+        let short = DataWrapper {
+            buf: vec![1],
+            max_len: 4,
+        };
+        let long = DataWrapper {
+            buf: vec![2, 3, 4],
+            max_len: 4,
+        };
+
+        let selected = DataWrapper::conditional_select(&short, &long, Choice::from(true));
+        assert_eq!(selected, long);
+        let selected = DataWrapper::conditional_select(&short, &long, Choice::from(false));
+        assert_eq!(selected, short);
+
+        let empty = DataWrapper {
+            buf: vec![],
+            max_len: 4,
+        };
+        let full = DataWrapper {
+            buf: vec![5, 6, 7, 8],
+            max_len: 4,
+        };
+        let selected = DataWrapper::conditional_select(&empty, &full, Choice::from(true));
+        assert_eq!(selected, full);
+        let selected = DataWrapper::conditional_select(&empty, &full, Choice::from(false));
+        assert_eq!(selected, empty);
+    }
 }
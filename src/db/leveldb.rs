@@ -11,34 +11,95 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
+//! LevelDB storage, locked so that several threads can share it
+
+#[cfg(feature = "parallel")]
+use crate::db::SharedStorage;
 use crate::db::Storage;
+use crate::{OramError, Result};
 use rusty_leveldb::{Options, DB as LDB};
 
 use std::path::Path;
-pub struct DB(LDB);
+use std::sync::Mutex;
+
+pub struct DB(Mutex<Handle>);
+
+/// A LevelDB handle that may move between threads
+struct Handle(LDB);
+
+// SAFETY: the handle keeps its state behind `Rc`s, which makes it `!Send`.
+// They are private to the handle: this module never calls the methods handing
+// out values that share them, such as iterators and snapshots. The handle is
+// only used through the mutex, so no two threads touch the counts at once.
+unsafe impl Send for Handle {}
 
 impl DB {
     /// Create a new LevelDB.
     ///
     /// - `name`: the name of the database
+    /// - `create_if_missing`: create the database if it does not exist
     ///
     /// # Returns
     ///
     /// It returns a tuple, where the first element is the Database and the
     /// second element indicates if the database is already existed
-    pub fn open(name: &'static str) -> (Self, bool) {
-        let opt = Options::default();
+    pub fn open(name: &str, create_if_missing: bool) -> Result<(Self, bool)> {
         let existed = Path::new(name).exists();
-        (DB(LDB::open(name, opt).expect("open leveldb")), existed)
+        if !existed && !create_if_missing {
+            return Err(OramError::NotFound);
+        }
+        let opt = Options {
+            create_if_missing,
+            ..Options::default()
+        };
+        let db = LDB::open(name, opt).map_err(|e| OramError::Storage(e.to_string()))?;
+        Ok((DB(Mutex::new(Handle(db))), existed))
+    }
+
+    fn handle(&mut self) -> &mut LDB {
+        &mut self.0.get_mut().expect("leveldb lock").0
     }
 }
 
 impl Storage for DB {
-    fn put(&mut self, key: &[u8], value: &[u8]) -> bool {
-        self.0.put(key, value).is_ok()
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.handle()
+            .put(key, value)
+            .map_err(|e| OramError::Storage(e.to_string()))
+    }
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.handle().get(key))
+    }
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.handle()
+            .delete(key)
+            .map_err(|e| OramError::Storage(e.to_string()))
+    }
+    fn flush(&mut self) -> Result<()> {
+        self.handle()
+            .flush()
+            .map_err(|e| OramError::Storage(e.to_string()))
+    }
+    #[cfg(feature = "parallel")]
+    fn shared(&self) -> Option<Box<dyn SharedStorage + '_>> {
+        Some(Box::new(self))
+    }
+}
+
+/// Requests of several threads take turns on the handle, while sealing and
+/// comparing blocks runs in parallel
+#[cfg(feature = "parallel")]
+impl SharedStorage for &DB {
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut handle = self.0.lock().expect("leveldb lock");
+        handle
+            .0
+            .put(key, value)
+            .map_err(|e| OramError::Storage(e.to_string()))
     }
-    fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
-        self.0.get(key)
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut handle = self.0.lock().expect("leveldb lock");
+        Ok(handle.0.get(key))
     }
 }
 
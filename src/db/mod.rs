@@ -12,17 +12,29 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::builder::Key;
+use crate::crypto::derive_key;
 use crate::fmt;
+use crate::string::String;
 use crate::vec::Vec;
 use crate::Box;
 use crate::HashMap;
+use crate::Result;
+use crate::RwLock;
 use cfg_if::cfg_if;
 
 #[cfg(feature = "std")]
 mod leveldb;
 
+mod sealed;
 #[cfg(feature = "sgx")]
 mod sgxfs;
+mod traced;
+
+use crate::trace::Recorder;
+pub use sealed::SealOptions;
+use sealed::Sealed;
+use traced::Traced;
 
 enum Persistence {
     #[allow(dead_code)]
@@ -38,35 +50,63 @@ pub struct Options {
     /// - `Some(LevelDb)`: Use leveldb. (Currently no available in SGX)
     /// - `Some(SgxFs)`: Use SGX protected fs. (Not available in std)
     persistence: Option<Persistence>,
+    /// Create the storage if it does not exist yet.
+    create_if_missing: bool,
+    /// Encrypt and/or authenticate values before they are stored.
+    seal: Option<SealOptions>,
+    /// Record the accesses reaching the backend.
+    trace: Option<Recorder>,
 }
 
 impl Options {
     pub fn in_memory() -> Self {
-        Options { persistence: None }
+        Self::with_persistence(None)
     }
 
     #[allow(dead_code)]
     pub fn leveldb() -> Self {
-        Options {
-            persistence: Some(Persistence::LevelDb),
-        }
+        Self::with_persistence(Some(Persistence::LevelDb))
     }
 
     #[cfg(feature = "sgx")]
     pub fn sgxfs() -> Self {
+        Self::with_persistence(Some(Persistence::SgxFs))
+    }
+
+    fn with_persistence(persistence: Option<Persistence>) -> Self {
         Options {
-            persistence: Some(Persistence::SgxFs),
+            persistence,
+            create_if_missing: true,
+            seal: None,
+            trace: None,
         }
     }
+
+    pub fn create_if_missing(mut self, create_if_missing: bool) -> Self {
+        self.create_if_missing = create_if_missing;
+        self
+    }
+
+    pub fn seal(mut self, seal: Option<SealOptions>) -> Self {
+        self.seal = seal;
+        self
+    }
+
+    pub fn trace(mut self, trace: Option<Recorder>) -> Self {
+        self.trace = trace;
+        self
+    }
 }
 
 pub struct Database {
     /// The name of the `Database`. It also affects the data directory name on file system.
-    name: &'static str,
+    name: String,
     /// The backend of the `Database`.
     backend: Box<dyn Storage>,
     /// If the database exists before it's opened.
     existed: bool,
+    /// The key sealing the values, if any
+    key: Option<Key>,
 }
 
 impl fmt::Debug for Database {
@@ -80,21 +120,37 @@ impl fmt::Debug for Database {
 }
 
 impl Database {
-    pub fn open(name: &'static str, opt: Options) -> Database {
-        match opt.persistence {
-            None => Self::new_memory(name),
-            Some(Persistence::LevelDb) => Self::new_leveldb(name),
+    /// Open the database `name`
+    ///
+    /// Fails with `OramError::NotFound` if it does not exist and
+    /// `opt.create_if_missing` is unset.
+    pub fn open(name: &str, opt: Options) -> Result<Database> {
+        let (backend, existed) = match opt.persistence {
+            None => Self::new_memory(),
+            Some(Persistence::LevelDb) => Self::new_leveldb(name, opt.create_if_missing)?,
             #[cfg(feature = "sgx")]
-            Some(Persistence::SgxFs) => Self::new_sgxfs(name),
-        }
+            Some(Persistence::SgxFs) => Self::new_sgxfs(name, opt.create_if_missing)?,
+        };
+        // below the sealing, so that the trace holds what the host sees
+        let backend = match opt.trace {
+            Some(recorder) => Box::new(Traced::new(backend, recorder)),
+            None => backend,
+        };
+        let key = opt.seal.as_ref().map(|seal| seal.key);
+        let backend = match opt.seal {
+            Some(seal) => Box::new(Sealed::new(backend, seal)),
+            None => backend,
+        };
+        Ok(Database {
+            name: name.into(),
+            backend,
+            existed,
+            key,
+        })
     }
 
-    fn new_memory(name: &'static str) -> Self {
-        Database {
-            name,
-            backend: Box::new(Memory::new()),
-            existed: false,
-        }
+    fn new_memory() -> (Box<dyn Storage>, bool) {
+        (Box::new(Memory::new()), false)
     }
 
     /// If the database exists before it's opened.
@@ -102,64 +158,142 @@ impl Database {
         self.existed
     }
 
+    /// A key for the purpose named by `label`, derived from the sealing key
+    ///
+    /// `None` if values are stored unsealed. The key is never stored.
+    pub fn derive_key(&self, label: &[u8]) -> Option<Key> {
+        self.key.map(|key| derive_key(&key, label))
+    }
+
     cfg_if! {
         if #[cfg(feature = "sgx")] {
-            fn new_leveldb(_name: &'static str) -> Self {
+            fn new_leveldb(_name: &str, _create_if_missing: bool) -> Result<(Box<dyn Storage>, bool)> {
                 //TODO: The development is ongoing, it will use https://github.com/mesalock-linux/rusty_leveldb_sgx
-                unimplemented!();
+                Err(crate::OramError::InvalidConfig("leveldb is not available in sgx".into()))
             }
 
-            fn new_sgxfs(name: &'static str) -> Self {
-                let (db, existed) = sgxfs::DB::open(name);
-                Database {
-                    name,
-                    backend: Box::new(db),
-                    existed
-                }
+            fn new_sgxfs(name: &str, create_if_missing: bool) -> Result<(Box<dyn Storage>, bool)> {
+                let (db, existed) = sgxfs::DB::open(name, create_if_missing)?;
+                Ok((Box::new(db), existed))
             }
         } else if #[cfg(feature = "std")] {
-            fn new_leveldb(name: &'static str) -> Self {
-                let (db, existed) = leveldb::DB::open(name);
-                Database {
-                    name,
-                    backend: Box::new(db),
-                    existed
-                }
+            fn new_leveldb(name: &str, create_if_missing: bool) -> Result<(Box<dyn Storage>, bool)> {
+                let (db, existed) = leveldb::DB::open(name, create_if_missing)?;
+                Ok((Box::new(db), existed))
             }
         }
     }
 
-    pub fn put(&mut self, key: &[u8], value: &[u8]) -> bool {
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
         self.backend.put(key, value)
     }
 
-    pub fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+    /// Remove `key`. Removing a missing key is not an error.
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.backend.delete(key)
+    }
+
+    /// Read the value stored at `key`. A key never written yields `Ok(None)`.
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
         self.backend.get(key)
     }
+
+    /// Read the value stored at `key` as the backend holds it, bypassing the sealing
+    pub fn get_unsealed(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.backend.get_unsealed(key)
+    }
+
+    /// Make all writes durable
+    pub fn flush(&mut self) -> Result<()> {
+        self.backend.flush()
+    }
+
+    /// The backend as storage for several threads, if it supports concurrent I/O
+    #[cfg(feature = "parallel")]
+    pub fn shared(&self) -> Option<Box<dyn SharedStorage + '_>> {
+        self.backend.shared()
+    }
+}
+
+/// Storage serving reads and writes from several threads at once
+#[cfg(feature = "parallel")]
+pub trait SharedStorage: Sync {
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<()>;
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
 }
 
 trait Storage {
-    fn put(&mut self, key: &[u8], value: &[u8]) -> bool;
-    fn get(&mut self, key: &[u8]) -> Option<Vec<u8>>;
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()>;
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    fn delete(&mut self, key: &[u8]) -> Result<()>;
+    /// Read a value bypassing decorators. Backends not wrapping another need not override it.
+    fn get_unsealed(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get(key)
+    }
+    /// Make all writes durable. Backends writing through need not override it.
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// The backend as storage for several threads. Backends serving one
+    /// request at a time need not override it; decorators forward it.
+    #[cfg(feature = "parallel")]
+    fn shared(&self) -> Option<Box<dyn SharedStorage + '_>> {
+        None
+    }
 }
 
+/// In-memory storage, locked so that several threads can share it
 struct Memory {
-    data: HashMap<Vec<u8>, Vec<u8>>,
+    data: RwLock<HashMap<Vec<u8>, Vec<u8>>>,
 }
 
 impl Memory {
     fn new() -> Self {
         Memory {
-            data: HashMap::new(),
+            data: RwLock::new(HashMap::new()),
         }
     }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut data = self.data.write().expect("memory storage lock");
+        data.insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let data = self.data.read().expect("memory storage lock");
+        Ok(data.get(key).cloned())
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<()> {
+        let mut data = self.data.write().expect("memory storage lock");
+        data.remove(key);
+        Ok(())
+    }
 }
 
 impl Storage for Memory {
-    fn put(&mut self, key: &[u8], value: &[u8]) -> bool {
-        self.data.insert(key.to_vec(), value.to_vec()).is_some()
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        Memory::put(self, key, value)
+    }
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Memory::get(self, key)
+    }
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        Memory::delete(self, key)
+    }
+    #[cfg(feature = "parallel")]
+    fn shared(&self) -> Option<Box<dyn SharedStorage + '_>> {
+        Some(Box::new(self))
+    }
+}
+
+#[cfg(feature = "parallel")]
+impl SharedStorage for &Memory {
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        Memory::put(self, key, value)
     }
-    fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
-        self.data.get(key).map(|k| k.clone())
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Memory::get(self, key)
     }
 }
@@ -0,0 +1,261 @@
+// Copyright 2020 ADVANCA PTE. LTD.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A storage decorator encrypting and authenticating values before they leave the enclave
+//!
+//! With encryption, values are sealed with XChaCha20-Poly1305 under a random 24-byte
+//! nonce drawn from `default_rng`, with the storage key as associated data, so a
+//! value moved to another key fails to open. With integrity alone, values are stored
+//! in the clear followed by a keyed BLAKE2b MAC over the storage key and the value.
+//!
+//! Rollback is out of scope: the host may replace a value with one sealed earlier
+//! under the same key and it will open, since the enclave keeps no trusted counter
+//! to tell the two apart.
+
+use crate::builder::{default_rng, Key};
+use crate::crypto::{cipher, constant_time_eq, derive_key, NONCE_LEN};
+#[cfg(feature = "parallel")]
+use crate::db::SharedStorage;
+use crate::db::Storage;
+use crate::vec::Vec;
+use crate::{Box, Input, OramError, Result, VarBlake2b, VariableOutput};
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+const MAC_LEN: usize = 32;
+
+/// Settings of the `Sealed` decorator
+#[derive(Clone)]
+pub struct SealOptions {
+    pub key: Key,
+    /// Encrypt stored values, which authenticates them as well
+    pub encryption: bool,
+    /// Authenticate stored values
+    pub integrity: bool,
+}
+
+pub struct Sealed {
+    inner: Box<dyn Storage>,
+    sealer: Sealer,
+}
+
+/// The keys sealing values, shared by all threads using a `Sealed`
+struct Sealer {
+    cipher: Option<XChaCha20Poly1305>,
+    mac_key: Option<Key>,
+}
+
+impl Sealed {
+    pub fn new(inner: Box<dyn Storage>, opt: SealOptions) -> Self {
+        let cipher = if opt.encryption {
+            Some(cipher(&opt.key, b"oram encryption"))
+        } else {
+            None
+        };
+        let mac_key = if opt.integrity && !opt.encryption {
+            Some(derive_key(&opt.key, b"oram integrity"))
+        } else {
+            None
+        };
+        Sealed {
+            inner,
+            sealer: Sealer { cipher, mac_key },
+        }
+    }
+}
+
+impl Sealer {
+    fn seal(&self, key: &[u8], value: &[u8]) -> Vec<u8> {
+        if let Some(cipher) = &self.cipher {
+            let mut nonce = [0u8; NONCE_LEN];
+            default_rng(&mut nonce);
+            let payload = Payload {
+                msg: value,
+                aad: key,
+            };
+            let ciphertext = cipher
+                .encrypt(&XNonce::from(nonce), payload)
+                .expect("value too long to encrypt");
+            let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+            sealed.extend_from_slice(&nonce);
+            sealed.extend_from_slice(&ciphertext);
+            return sealed;
+        }
+        let mut sealed = Vec::with_capacity(value.len() + MAC_LEN);
+        sealed.extend_from_slice(value);
+        if let Some(mac_key) = &self.mac_key {
+            let mac = compute_mac(mac_key, key, &sealed);
+            sealed.extend_from_slice(&mac);
+        }
+        sealed
+    }
+
+    fn unseal(&self, key: &[u8], mut sealed: Vec<u8>) -> Result<Vec<u8>> {
+        if let Some(cipher) = &self.cipher {
+            if sealed.len() < NONCE_LEN {
+                return Err(OramError::Integrity(None));
+            }
+            let mut nonce = [0u8; NONCE_LEN];
+            nonce.copy_from_slice(&sealed[..NONCE_LEN]);
+            let payload = Payload {
+                msg: &sealed[NONCE_LEN..],
+                aad: key,
+            };
+            return cipher
+                .decrypt(&XNonce::from(nonce), payload)
+                .map_err(|_| OramError::Integrity(None));
+        }
+        if let Some(mac_key) = &self.mac_key {
+            if sealed.len() < MAC_LEN {
+                return Err(OramError::Integrity(None));
+            }
+            let mac = sealed.split_off(sealed.len() - MAC_LEN);
+            if !constant_time_eq(&mac, &compute_mac(mac_key, key, &sealed)) {
+                return Err(OramError::Integrity(None));
+            }
+        }
+        Ok(sealed)
+    }
+}
+
+impl Storage for Sealed {
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.inner.put(key, &self.sealer.seal(key, value))
+    }
+
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.inner.get(key)? {
+            Some(sealed) => self.sealer.unseal(key, sealed).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.inner.delete(key)
+    }
+
+    fn get_unsealed(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get_unsealed(key)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    #[cfg(feature = "parallel")]
+    fn shared(&self) -> Option<Box<dyn SharedStorage + '_>> {
+        let inner = self.inner.shared()?;
+        Some(Box::new(SharedSealed {
+            inner,
+            sealer: &self.sealer,
+        }))
+    }
+}
+
+/// A `Sealed` over a backend with concurrent I/O
+#[cfg(feature = "parallel")]
+struct SharedSealed<'a> {
+    inner: Box<dyn SharedStorage + 'a>,
+    sealer: &'a Sealer,
+}
+
+#[cfg(feature = "parallel")]
+impl SharedStorage for SharedSealed<'_> {
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.inner.put(key, &self.sealer.seal(key, value))
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.inner.get(key)? {
+            Some(sealed) => self.sealer.unseal(key, sealed).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+fn compute_mac(key: &Key, storage_key: &[u8], sealed: &[u8]) -> Vec<u8> {
+    let mut hasher = VarBlake2b::new_keyed(key, MAC_LEN);
+    hasher.input((storage_key.len() as u64).to_le_bytes());
+    hasher.input(storage_key);
+    hasher.input(sealed);
+    hasher.vec_result()
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::crypto::TAG_LEN;
+    use crate::db::Memory;
+
+    fn sealed(encryption: bool, integrity: bool) -> Sealed {
+        Sealed::new(
+            Box::new(Memory::new()),
+            SealOptions {
+                key: [7; 32],
+                encryption,
+                integrity,
+            },
+        )
+    }
+
+    #[test]
+    fn round_trip() {
+        for &(encryption, integrity) in &[(true, true), (true, false), (false, true)] {
+            let mut db = sealed(encryption, integrity);
+            let value = (0..200).map(|i| i as u8).collect::<Vec<u8>>();
+            db.put(b"key", &value).unwrap();
+            assert_eq!(db.get(b"key").unwrap(), Some(value));
+            assert_eq!(db.get(b"missing").unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn ciphertext_hides_value() {
+        let mut db = sealed(true, false);
+        let value = vec![0u8; 100];
+        db.put(b"key", &value).unwrap();
+        let stored = db.inner.get(b"key").unwrap().unwrap();
+        assert_eq!(stored.len(), NONCE_LEN + value.len() + TAG_LEN);
+        assert_ne!(&stored[NONCE_LEN..NONCE_LEN + value.len()], &value[..]);
+
+        // a fresh nonce every time, so equal values do not look equal
+        db.put(b"key", &value).unwrap();
+        assert_ne!(db.inner.get(b"key").unwrap().unwrap(), stored);
+    }
+
+    #[test]
+    fn detect_tampering() {
+        for &(encryption, integrity) in &[(true, true), (true, false), (false, true)] {
+            let mut db = sealed(encryption, integrity);
+            db.put(b"key", &[1, 2, 3]).unwrap();
+            let mut stored = db.inner.get(b"key").unwrap().unwrap();
+            stored[0] ^= 1;
+            db.inner.put(b"key", &stored).unwrap();
+            assert_eq!(db.get(b"key"), Err(OramError::Integrity(None)));
+            db.inner.put(b"key", &[0; 8]).unwrap();
+            assert_eq!(db.get(b"key"), Err(OramError::Integrity(None)));
+        }
+    }
+
+    #[test]
+    fn detect_moved_value() {
+        for &(encryption, integrity) in &[(true, false), (false, true)] {
+            let mut db = sealed(encryption, integrity);
+            db.put(b"a", &[1, 2, 3]).unwrap();
+            let stored = db.inner.get(b"a").unwrap().unwrap();
+            db.inner.put(b"b", &stored).unwrap();
+            assert_eq!(db.get(b"b"), Err(OramError::Integrity(None)));
+        }
+    }
+}
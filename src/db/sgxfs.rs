@@ -23,13 +23,16 @@ use sgx_tstd::path::PathBuf;
 use sgx_tstd::prelude::v1::*;
 use sgx_tstd::vec;
 
+#[cfg(feature = "parallel")]
+use crate::db::SharedStorage;
 use crate::db::Storage;
+use crate::{OramError, Result};
 use bincode_sgx::{deserialize, serialize};
 use serde_derive_sgx::{Deserialize, Serialize};
 
 #[derive(Debug)]
 pub struct DB {
-    name: &'static str,
+    name: String,
     path: PathBuf,
 }
 
@@ -48,17 +51,23 @@ impl DB {
     /// This function returns a tuple, where
     /// - `0`: The DB
     /// - `1`: if the database (directory) exists
-    pub fn open(name: &'static str) -> (Self, bool) {
+    pub fn open(name: &str, create_if_missing: bool) -> Result<(Self, bool)> {
         let path = PathBuf::from(name);
         let existed = path.exists();
 
         if !existed {
-            fs::create_dir_all(path.as_path()).expect("create directory");
+            if !create_if_missing {
+                return Err(OramError::NotFound);
+            }
+            fs::create_dir_all(path.as_path()).map_err(|e| OramError::Storage(e.to_string()))?;
         }
 
-        let db = DB { name, path };
+        let db = DB {
+            name: name.into(),
+            path,
+        };
 
-        (db, existed)
+        Ok((db, existed))
     }
 }
 
@@ -69,8 +78,8 @@ fn data_filename(key: &[u8]) -> String {
     hex_encode(&hash)
 }
 
-impl Storage for DB {
-    fn put(&mut self, key: &[u8], value: &[u8]) -> bool {
+impl DB {
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
         // open file based on key name
         let mut p = self.path.clone();
         p.push(data_filename(key));
@@ -79,33 +88,72 @@ impl Storage for DB {
             .write(true)
             .append(false)
             .open(p.as_path())
-            .expect("open file to write");
+            .map_err(|e| OramError::Storage(e.to_string()))?;
         let data_file = DataFile {
             key: key.to_vec(),
             value: value.to_vec(),
         };
-        f.write(&serialize(&data_file).expect("serialize DataFile"))
-            .is_ok()
+        f.write_all(&serialize(&data_file).expect("serialize DataFile"))
+            .map_err(|e| OramError::Storage(e.to_string()))
     }
 
-    fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
         // open file based on key name
         let mut p = self.path.clone();
         p.push(data_filename(key));
 
+        if !p.exists() {
+            return Ok(None);
+        }
+
         let mut f = protected_fs::OpenOptions::default()
             .read(true)
             .open(p.as_path())
-            .expect("open file to read");
+            .map_err(|e| OramError::Storage(e.to_string()))?;
 
         let mut buf = vec![];
+        f.read_to_end(&mut buf)
+            .map_err(|e| OramError::Storage(e.to_string()))?;
+
+        let data_file: DataFile =
+            deserialize(&buf).map_err(|e| OramError::Storage(e.to_string()))?;
+        Ok(Some(data_file.value))
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<()> {
+        let mut p = self.path.clone();
+        p.push(data_filename(key));
 
-        if f.read_to_end(&mut buf).is_err() {
-            //TODO: better error handling
-            return None;
+        if !p.exists() {
+            return Ok(());
         }
+        fs::remove_file(p.as_path()).map_err(|e| OramError::Storage(e.to_string()))
+    }
+}
+
+impl Storage for DB {
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        DB::put(self, key, value)
+    }
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        DB::get(self, key)
+    }
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        DB::delete(self, key)
+    }
+    #[cfg(feature = "parallel")]
+    fn shared(&self) -> Option<Box<dyn SharedStorage + '_>> {
+        Some(Box::new(self))
+    }
+}
 
-        let data_file: DataFile = deserialize(&buf).expect("deserialize DataFile");
-        Some(data_file.value)
+/// Every key is a file of its own, so threads write to different files
+#[cfg(feature = "parallel")]
+impl SharedStorage for &DB {
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        DB::put(self, key, value)
+    }
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        DB::get(self, key)
     }
 }
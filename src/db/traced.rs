@@ -0,0 +1,92 @@
+// Copyright 2020 ADVANCA PTE. LTD.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A storage decorator recording every access into a `trace::Recorder`
+//!
+//! It wraps the backend below any sealing, so it records what the host sees.
+
+#[cfg(feature = "parallel")]
+use crate::db::SharedStorage;
+use crate::db::Storage;
+use crate::trace::{Event, Op, Recorder};
+use crate::vec::Vec;
+use crate::{Box, Result};
+
+pub struct Traced {
+    inner: Box<dyn Storage>,
+    recorder: Recorder,
+}
+
+impl Traced {
+    pub fn new(inner: Box<dyn Storage>, recorder: Recorder) -> Self {
+        Traced { inner, recorder }
+    }
+}
+
+impl Storage for Traced {
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.recorder
+            .record(Event::new(Op::Put, key, Some(value)))?;
+        self.inner.put(key, value)
+    }
+
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let value = self.inner.get(key)?;
+        self.recorder
+            .record(Event::new(Op::Get, key, value.as_deref()))?;
+        Ok(value)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.recorder.record(Event::new(Op::Delete, key, None))?;
+        self.inner.delete(key)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()?;
+        self.recorder.flush()
+    }
+
+    #[cfg(feature = "parallel")]
+    fn shared(&self) -> Option<Box<dyn SharedStorage + '_>> {
+        let inner = self.inner.shared()?;
+        Some(Box::new(SharedTraced {
+            inner,
+            recorder: &self.recorder,
+        }))
+    }
+}
+
+/// A `Traced` over a backend with concurrent I/O
+#[cfg(feature = "parallel")]
+struct SharedTraced<'a> {
+    inner: Box<dyn SharedStorage + 'a>,
+    recorder: &'a Recorder,
+}
+
+#[cfg(feature = "parallel")]
+impl SharedStorage for SharedTraced<'_> {
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.recorder
+            .record(Event::new(Op::Put, key, Some(value)))?;
+        self.inner.put(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let value = self.inner.get(key)?;
+        self.recorder
+            .record(Event::new(Op::Get, key, value.as_deref()))?;
+        Ok(value)
+    }
+}
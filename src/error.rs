@@ -0,0 +1,87 @@
+// Copyright 2020 ADVANCA PTE. LTD.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#[cfg(feature = "sgx")]
+use sgx_tstd::{self as std, prelude::v1::*};
+
+use std::fmt;
+use std::string::String;
+
+/// Errors returned by ORAM operations
+///
+/// The storage host is not trusted, so anything it hands back may be missing,
+/// replaced or garbled. Such cases are reported instead of aborting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OramError {
+    /// The storage backend failed to read or write
+    Storage(String),
+    /// A block is missing or differs from what was written.
+    ///
+    /// Contains the physical position of the block, if known.
+    Integrity(Option<u64>),
+    /// A block at the given physical position cannot be decoded
+    Corruption(u64),
+    /// The data is longer than the block size
+    Capacity { len: usize, block_size: usize },
+    /// The key is not in `0..n`
+    KeyOutOfRange { key: u64, n: u64 },
+    /// There is no free slot for a new entry
+    Full,
+    /// A value cannot be serialized or deserialized
+    Serialization(String),
+    /// The requested configuration is not valid
+    InvalidConfig(String),
+    /// The store does not exist but is required to
+    NotFound,
+    /// The store exists but is required not to
+    AlreadyExists,
+    /// The store has a format version this crate does not open.
+    ///
+    /// Older versions are converted by `OramBuilder::migrate()`.
+    UnsupportedFormat(u32),
+}
+
+/// Result type of ORAM operations
+pub type Result<T> = std::result::Result<T, OramError>;
+
+impl fmt::Display for OramError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OramError::Storage(msg) => write!(formatter, "storage error: {}", msg),
+            OramError::Integrity(Some(position)) => {
+                write!(formatter, "integrity check failed for block {}", position)
+            }
+            OramError::Integrity(None) => write!(formatter, "integrity check failed"),
+            OramError::Corruption(position) => write!(formatter, "block {} is corrupt", position),
+            OramError::Capacity { len, block_size } => write!(
+                formatter,
+                "data length {} exceeds block size {}",
+                len, block_size
+            ),
+            OramError::KeyOutOfRange { key, n } => {
+                write!(formatter, "key {} is out of range 0..{}", key, n)
+            }
+            OramError::Full => write!(formatter, "no free slot for a new entry"),
+            OramError::Serialization(msg) => write!(formatter, "serialization error: {}", msg),
+            OramError::InvalidConfig(msg) => write!(formatter, "invalid configuration: {}", msg),
+            OramError::NotFound => write!(formatter, "store not found"),
+            OramError::AlreadyExists => write!(formatter, "store already exists"),
+            OramError::UnsupportedFormat(version) => {
+                write!(formatter, "unsupported store format version {}", version)
+            }
+        }
+    }
+}
+
+impl std::error::Error for OramError {}
@@ -0,0 +1,384 @@
+// Copyright 2020 ADVANCA PTE. LTD.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Versions of the on-disk format
+//!
+//! - Version 1 has no header and no journal. Blocks are stored under 4-byte
+//!   keys, with a 32-bit tag and index where index `u32::MAX` marks a dummy
+//!   block.
+//! - Version 2 stores blocks under 8-byte keys, with 64-bit indices and the
+//!   dummy and occupancy flags kept out of band, next to a journal and a header.
+//!
+//! The header is written when a store is created, so a store without one is
+//! either of version 1 or damaged. `OramBuilder::migrate()` converts version 1
+//! stores.
+
+use crate::builder::default_shelter_size;
+use crate::data::DataWrapper;
+use crate::db::Database;
+use crate::journal::Journal;
+use crate::{deserialize, serialize, Block, BlockCache, Deserialize, Serialize};
+use crate::{OramError, Result, SqrtOram};
+
+/// The version of stores written by this crate
+pub const FORMAT_VERSION: u32 = 2;
+
+/// Storage key of the header, distinct from the keys of blocks of any version
+const HEADER_KEY: &[u8] = b"header";
+
+/// Parameters of a store, checked when it is opened
+#[cfg_attr(feature = "sgx", serde(crate = "serde_sgx"))]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) struct StoreHeader {
+    pub version: u32,
+    pub n: u64,
+    pub shelter_size: u64,
+    pub block_size: u64,
+}
+
+/// Decode a block of the current version
+pub(crate) fn decode_block(data: &[u8]) -> Option<Block> {
+    deserialize(data).ok()
+}
+
+mod v1 {
+    use super::*;
+
+    /// Index marking a dummy block
+    pub const DUMMY_INDEX: u32 = u32::MAX;
+
+    #[cfg_attr(feature = "sgx", serde(crate = "serde_sgx"))]
+    #[derive(Serialize, Deserialize)]
+    pub struct BlockCache {
+        pub tag: u32,
+        pub index: u32,
+    }
+
+    #[cfg_attr(feature = "sgx", serde(crate = "serde_sgx"))]
+    #[derive(Serialize, Deserialize)]
+    pub struct Block {
+        pub header: BlockCache,
+        pub data: DataWrapper,
+    }
+
+    pub fn key(i: u64) -> [u8; 4] {
+        (i as u32).to_be_bytes()
+    }
+}
+
+/// Decode a block of version 1 in a store of `n` real blocks
+///
+/// Version 1 does not tell written keys from unwritten ones, so every real
+/// block is taken as occupied, returning what version 1 returned.
+fn decode_block_v1(data: &[u8], n: u64) -> Option<Block> {
+    let block: v1::Block = deserialize(data).ok()?;
+    let index = u64::from(block.header.index);
+    let dummy = block.header.index == v1::DUMMY_INDEX;
+    Some(Block {
+        header: BlockCache {
+            tag: 0,
+            index: if dummy { 0 } else { index },
+            dummy,
+            occupied: !dummy && index < n,
+        },
+        data: block.data,
+    })
+}
+
+fn read_header(db: &mut Database) -> Result<Option<StoreHeader>> {
+    match db.get(HEADER_KEY)? {
+        Some(data) => deserialize(&data)
+            .map(Some)
+            .map_err(|e| OramError::Serialization(e.to_string())),
+        None => Ok(None),
+    }
+}
+
+fn write_header(db: &mut Database, header: &StoreHeader) -> Result<()> {
+    db.put(HEADER_KEY, &serialize(header).expect("serialize header"))
+}
+
+/// The format version of an existing store
+fn detect_version(db: &mut Database) -> Result<u32> {
+    if let Some(header) = read_header(db)? {
+        return Ok(header.version);
+    }
+    // version 1 stores were never sealed, so they are read past the decorator
+    if db.get_unsealed(&v1::key(0))?.is_some() {
+        Ok(1)
+    } else {
+        Err(OramError::Integrity(None))
+    }
+}
+
+/// Rewrite a version 1 store of `n` blocks into the current format
+///
+/// Every old block is read once and written once, in the order of positions,
+/// so the access pattern depends on `n` alone. The header is written last: an
+/// interrupted migration leaves a version 1 store and can be run again. The old
+/// blocks are not removed, as their keys do not collide with the new ones.
+pub(crate) fn migrate(db: &mut Database, n: u64, block_size: usize) -> Result<()> {
+    match detect_version(db)? {
+        FORMAT_VERSION => return Ok(()),
+        1 => {}
+        version => return Err(OramError::UnsupportedFormat(version)),
+    }
+
+    let shelter_size = default_shelter_size(n);
+    for i in 0..n + 2 * shelter_size {
+        let data = db
+            .get_unsealed(&v1::key(i))?
+            .ok_or(OramError::Integrity(Some(i)))?;
+        let block = decode_block_v1(&data, n).ok_or(OramError::Corruption(i))?;
+        if block.data.max_len != block_size {
+            return Err(OramError::InvalidConfig(
+                "the block size differs from the store".into(),
+            ));
+        }
+        db.put(
+            &i.to_be_bytes(),
+            &serialize(&block).expect("serialize block"),
+        )?;
+    }
+    // the tags of the new blocks are not derived yet, so the blocks have to be sorted
+    db.put(
+        crate::journal::JOURNAL_KEY,
+        &serialize(&Journal::Shuffled).expect("serialize journal"),
+    )?;
+    write_header(
+        db,
+        &StoreHeader {
+            version: FORMAT_VERSION,
+            n,
+            shelter_size,
+            block_size: block_size as u64,
+        },
+    )?;
+    db.flush()
+}
+
+impl SqrtOram {
+    fn store_header(&self) -> StoreHeader {
+        StoreHeader {
+            version: FORMAT_VERSION,
+            n: self.n,
+            shelter_size: self.shelter_size,
+            block_size: self.block_size as u64,
+        }
+    }
+
+    /// Record the parameters of the store
+    pub(crate) fn write_header(&mut self) -> Result<()> {
+        let header = self.store_header();
+        write_header(&mut self.db, &header)
+    }
+
+    /// Check that an existing store has the current format and the configured parameters
+    pub(crate) fn check_header(&mut self) -> Result<()> {
+        let header = match read_header(&mut self.db)? {
+            Some(header) => header,
+            // creation was interrupted before the header, `recover()` starts over
+            None if matches!(self.read_journal()?, Some(Journal::Init)) => {
+                return self.write_header();
+            }
+            None => return Err(OramError::UnsupportedFormat(detect_version(&mut self.db)?)),
+        };
+        if header.version != FORMAT_VERSION {
+            return Err(OramError::UnsupportedFormat(header.version));
+        }
+        if header != self.store_header() && !self.resizing(&header)? {
+            return Err(OramError::InvalidConfig(
+                "the number of blocks, shelter size or block size differs from the store".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether an interrupted resize explains the difference to `header`, so
+    /// that the store opens with the `n` before or after the resize
+    fn resizing(&mut self, header: &StoreHeader) -> Result<bool> {
+        Ok(match self.read_journal()? {
+            Some(Journal::Resized(from, to, _)) => {
+                let sizes = [from, to];
+                sizes.contains(&header.n)
+                    && sizes.contains(&self.n)
+                    && header.block_size == self.block_size as u64
+            }
+            _ => false,
+        })
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::builder::default_rng;
+    use crate::db;
+
+    const N: u64 = 16;
+    const BLOCK_SIZE: usize = 8;
+
+    /// Write the blocks of a version 1 store as its `init_blocks()` and `put()` did
+    fn write_v1_store(db: &mut Database) {
+        let capacity = N + 2 * default_shelter_size(N);
+        for i in 0..capacity {
+            let index = if i < N + default_shelter_size(N) {
+                i as u32
+            } else {
+                v1::DUMMY_INDEX
+            };
+            let mut buf = vec![0; BLOCK_SIZE];
+            default_rng(&mut buf);
+            if i < N {
+                buf = vec![i as u8; 3];
+            }
+            let block = v1::Block {
+                header: v1::BlockCache { tag: 0, index },
+                data: DataWrapper {
+                    buf,
+                    max_len: BLOCK_SIZE,
+                },
+            };
+            db.put(&v1::key(i), &serialize(&block).unwrap()).unwrap();
+        }
+    }
+
+    fn memory_db() -> Database {
+        Database::open("in-memory", db::Options::in_memory()).unwrap()
+    }
+
+    #[test]
+    fn decode_v1_blocks() {
+        let block = v1::Block {
+            header: v1::BlockCache { tag: 3, index: 5 },
+            data: DataWrapper {
+                buf: vec![1, 2],
+                max_len: 4,
+            },
+        };
+        let decoded = decode_block_v1(&serialize(&block).unwrap(), 4).unwrap();
+        assert_eq!(decoded.header.index, 5);
+        assert!(!decoded.header.dummy && !decoded.header.occupied);
+        assert_eq!(decoded.data.buf, vec![1, 2]);
+
+        let block = v1::Block {
+            header: v1::BlockCache {
+                tag: 3,
+                index: v1::DUMMY_INDEX,
+            },
+            ..block
+        };
+        let decoded = decode_block_v1(&serialize(&block).unwrap(), 4).unwrap();
+        assert!(decoded.header.dummy && !decoded.header.occupied);
+    }
+
+    #[test]
+    fn migrate_v1_store() {
+        let mut db = memory_db();
+        write_v1_store(&mut db);
+        assert_eq!(detect_version(&mut db), Ok(1));
+
+        migrate(&mut db, N, BLOCK_SIZE).unwrap();
+        assert_eq!(detect_version(&mut db), Ok(FORMAT_VERSION));
+        for i in 0..N {
+            let block = decode_block(&db.get(&i.to_be_bytes()).unwrap().unwrap()).unwrap();
+            assert!(block.header.occupied);
+            assert_eq!(block.data.buf, vec![i as u8; 3]);
+        }
+        // migrating again does nothing
+        migrate(&mut db, N, BLOCK_SIZE).unwrap();
+    }
+
+    #[test]
+    fn reject_other_versions_and_parameters() {
+        let mut oram = SqrtOram::new(N, BLOCK_SIZE).unwrap();
+        oram.check_header().unwrap();
+
+        oram.n = N + 1;
+        assert!(matches!(
+            oram.check_header(),
+            Err(OramError::InvalidConfig(_))
+        ));
+        oram.n = N;
+
+        let mut header = oram.store_header();
+        header.version = FORMAT_VERSION + 1;
+        write_header(&mut oram.db, &header).unwrap();
+        assert_eq!(
+            oram.check_header(),
+            Err(OramError::UnsupportedFormat(FORMAT_VERSION + 1))
+        );
+
+        let mut db = memory_db();
+        write_v1_store(&mut db);
+        assert_eq!(
+            migrate(&mut db, N, BLOCK_SIZE + 1),
+            Err(OramError::InvalidConfig(
+                "the block size differs from the store".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn finish_interrupted_creation() {
+        let mut oram = SqrtOram::new(N, BLOCK_SIZE).unwrap();
+        oram.put(1, vec![1]).unwrap();
+
+        // crash after the journal of a new store, before its header
+        oram.write_journal(&Journal::Init).unwrap();
+        oram.db.delete(HEADER_KEY).unwrap();
+        oram.check_header().unwrap();
+        assert_eq!(
+            read_header(&mut oram.db).unwrap(),
+            Some(oram.store_header())
+        );
+        oram.recover().unwrap();
+        oram.permute().unwrap();
+        assert_eq!(oram.get(1).unwrap(), None);
+    }
+
+    #[test]
+    #[ignore]
+    // Ignore this test as it needs leveldb on disk
+    fn leveldb_migrate() {
+        use crate::{Backend, OramBuilder};
+
+        let db_name = "db_migrate";
+        let mut db = Database::open(db_name, db::Options::leveldb()).unwrap();
+        write_v1_store(&mut db);
+        db.flush().unwrap();
+        drop(db);
+
+        let builder = || {
+            OramBuilder::new(N, BLOCK_SIZE)
+                .backend(Backend::LevelDb)
+                .path(db_name)
+        };
+        assert_eq!(
+            builder().build().err(),
+            Some(OramError::UnsupportedFormat(1))
+        );
+        let mut oram = builder().migrate().unwrap();
+        for i in 0..N {
+            assert_eq!(oram.get(i).unwrap(), Some(vec![i as u8; 3]));
+        }
+        oram.close().unwrap();
+
+        let mut oram = builder().build().unwrap();
+        assert_eq!(oram.len(), N);
+        assert_eq!(oram.get(1).unwrap(), Some(vec![1; 3]));
+        drop(oram);
+        std::fs::remove_dir_all(db_name).unwrap();
+    }
+}
@@ -0,0 +1,376 @@
+// Copyright 2020 ADVANCA PTE. LTD.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Write-ahead journal keeping the store recoverable after a crash
+//!
+//! Every single block write is atomic, so the store only becomes inconsistent
+//! when a block is moved with two writes: the swaps of the sorting networks and
+//! the move of a block from the permuted area to the shelter. Such pairs are
+//! logged in the journal before they are written, together with the state the
+//! journal moves to once both are written. A sort in chunks logs all the
+//! blocks of a step the same way.
+//!
+//! Between accesses the journal only records that the blocks are permuted for
+//! an epoch. A store is rearranged and shuffled under a new salt whenever it
+//! reopens, so neither the salt nor the access count is stored.
+
+use crate::vec::Vec;
+use crate::{deserialize, serialize, Block, Deserialize, OramError, Result, Serialize};
+use crate::{Box, SqrtOram};
+
+/// Storage key of the journal, distinct from the 8-byte keys of blocks
+pub(crate) const JOURNAL_KEY: &[u8] = b"journal";
+
+/// Storage key of the journal slot of swaps starting at position `i`
+///
+/// Parallel sorts journal every swap in its own slot, as the swaps of one
+/// layer run at once. A slot holds `Journal::Pair` while its swap is written
+/// and `Journal::Shuffled` afterwards.
+pub(crate) fn slot_key(i: u64) -> Vec<u8> {
+    let mut key = JOURNAL_KEY.to_vec();
+    key.extend_from_slice(&i.to_be_bytes());
+    key
+}
+
+#[cfg_attr(feature = "sgx", serde(crate = "serde_sgx"))]
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) enum Journal {
+    /// The blocks are being initialized
+    Init,
+    /// Block `k` is at position `k`
+    Rearranged,
+    /// The blocks are in any consistent order
+    Shuffled,
+    /// The blocks are permuted for an epoch
+    Epoch,
+    /// Two blocks are being written to the given positions, then the journal
+    /// becomes the last field
+    Pair(u64, Block, u64, Block, Box<Journal>),
+    /// The blocks of the permuted area are in the cells of a Melbourne shuffle
+    Distributed,
+    /// Blocks are being written to the given positions, then the journal
+    /// becomes the last field
+    Batch(Vec<(u64, Block)>, Box<Journal>),
+    /// Rearranged blocks are being laid out for another number of real
+    /// blocks: the old and new `n`, and the end of the positions of both layouts
+    Resized(u64, u64, u64),
+}
+
+impl SqrtOram {
+    pub(crate) fn read_journal(&mut self) -> Result<Option<Journal>> {
+        match self.db.get(JOURNAL_KEY)? {
+            Some(data) => deserialize(&data)
+                .map(Some)
+                .map_err(|e| OramError::Serialization(e.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn write_journal(&mut self, journal: &Journal) -> Result<()> {
+        self.db
+            .put(JOURNAL_KEY, &serialize(journal).expect("serialize journal"))
+    }
+
+    /// Write two blocks as one recoverable step, then move the journal to `next`
+    pub(crate) fn write_pair(
+        &mut self,
+        i: u64,
+        a: &Block,
+        j: u64,
+        b: &Block,
+        next: Journal,
+    ) -> Result<()> {
+        let pair = Journal::Pair(i, a.clone(), j, b.clone(), Box::new(next.clone()));
+        self.write_journal(&pair)?;
+        self.write_block(i, a)?;
+        self.write_block(j, b)?;
+        self.write_journal(&next)
+    }
+
+    /// Write several blocks as one recoverable step, then move the journal to `next`
+    pub(crate) fn write_batch(&mut self, blocks: &[(u64, Block)], next: Journal) -> Result<()> {
+        let batch = Journal::Batch(blocks.to_vec(), Box::new(next.clone()));
+        self.write_journal(&batch)?;
+        for (i, block) in blocks {
+            self.write_block(*i, block)?;
+        }
+        self.write_journal(&next)
+    }
+
+    /// Finish the swaps of an interrupted parallel sort
+    fn replay_slots(&mut self) -> Result<()> {
+        for k in 0..self.capacity {
+            let slot = slot_key(k);
+            let record = match self.db.get(&slot)? {
+                Some(data) => {
+                    deserialize(&data).map_err(|e| OramError::Serialization(e.to_string()))?
+                }
+                None => continue,
+            };
+            if let Journal::Pair(i, a, j, b, _) = record {
+                self.write_block(i, &a)?;
+                self.write_block(j, &b)?;
+                self.db.put(
+                    &slot,
+                    &serialize(&Journal::Shuffled).expect("serialize journal"),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Bring an existing store to a consistent state
+    ///
+    /// The blocks are left rearranged and need a `permute()`. An interrupted
+    /// epoch is never resumed: its access count comes from the host, which
+    /// could replay an older journal to have dummy blocks fetched again.
+    pub(crate) fn recover(&mut self) -> Result<()> {
+        let state = match self.read_journal()? {
+            Some(Journal::Pair(i, a, j, b, next)) => {
+                self.write_block(i, &a)?;
+                self.write_block(j, &b)?;
+                Some(*next)
+            }
+            Some(Journal::Batch(blocks, next)) => {
+                for (i, block) in &blocks {
+                    self.write_block(*i, block)?;
+                }
+                Some(*next)
+            }
+            state => state,
+        };
+        let state = match state {
+            Some(Journal::Shuffled) => {
+                self.replay_slots()?;
+                state
+            }
+            Some(Journal::Distributed) => {
+                self.collect_scratch()?;
+                state
+            }
+            Some(Journal::Resized(from, to, end)) => {
+                // finish or roll back, whichever `n` the store is opened with
+                self.lay_out(from.min(to), end)?;
+                self.write_journal(&Journal::Rearranged)?;
+                Some(Journal::Rearranged)
+            }
+            state => state,
+        };
+        match state {
+            // nothing was stored before initialization completed
+            Some(Journal::Init) => return self.init_blocks(),
+            // the journal is written before the header, so it was removed
+            None => return Err(OramError::Integrity(None)),
+            _ => {}
+        }
+
+        self.warm_up_cache()?;
+        self.len = self.cache.iter().filter(|c| !c.dummy && c.occupied).count() as u64;
+        match state {
+            Some(Journal::Rearranged) => Ok(()),
+            _ => {
+                self.write_journal(&Journal::Shuffled)?;
+                self.rearrange()
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::builder::default_shelter_size;
+
+    fn filled_oram(n: u64) -> SqrtOram {
+        let mut oram = SqrtOram::new(n, 8).unwrap();
+        for i in 0..n {
+            oram.put(i, vec![i as u8]).unwrap();
+        }
+        oram
+    }
+
+    /// Do what opening the store again does
+    fn reopen(oram: &mut SqrtOram) {
+        oram.cache.iter_mut().for_each(|c| *c = Default::default());
+        oram.scanning = false;
+        oram.recover().unwrap();
+        oram.permute().unwrap();
+    }
+
+    fn check_contents(oram: &mut SqrtOram) {
+        assert_eq!(oram.len(), oram.num_blocks());
+        for i in 0..oram.num_blocks() {
+            assert_eq!(oram.get(i).unwrap(), Some(vec![i as u8]));
+        }
+    }
+
+    #[test]
+    fn new_epoch_on_reopen() {
+        let mut oram = filled_oram(16);
+        oram.get(0).unwrap();
+        oram.get(1).unwrap();
+        let salt = oram.salt;
+        assert!(oram.count > 0);
+
+        // the journal tells the host nothing about the epoch
+        let journal = oram.db.get(JOURNAL_KEY).unwrap().unwrap();
+        assert_eq!(journal, serialize(&Journal::Epoch).unwrap());
+        reopen(&mut oram);
+        assert_ne!(oram.salt, salt);
+        assert_eq!(oram.count, 0);
+        check_contents(&mut oram);
+    }
+
+    #[test]
+    fn recover_interrupted_swap() {
+        let mut oram = filled_oram(16);
+        let (i, j) = (2, 9);
+        let a = oram.read_block(i).unwrap();
+        let b = oram.read_block(j).unwrap();
+
+        // crash after the first write of a swap
+        let pair = Journal::Pair(i, b.clone(), j, a, Box::new(Journal::Shuffled));
+        oram.write_journal(&pair).unwrap();
+        oram.write_block(i, &b).unwrap();
+
+        reopen(&mut oram);
+        check_contents(&mut oram);
+    }
+
+    #[test]
+    fn recover_interrupted_parallel_swap() {
+        let mut oram = filled_oram(16);
+        let (i, j) = (3, 12);
+        let a = oram.read_block(i).unwrap();
+        let b = oram.read_block(j).unwrap();
+
+        // crash after the first write of a swap journaled in its slot
+        let pair = Journal::Pair(i, b.clone(), j, a, Box::new(Journal::Shuffled));
+        oram.write_journal(&Journal::Shuffled).unwrap();
+        oram.db
+            .put(&slot_key(i), &serialize(&pair).unwrap())
+            .unwrap();
+        oram.write_block(i, &b).unwrap();
+
+        reopen(&mut oram);
+        check_contents(&mut oram);
+    }
+
+    #[test]
+    fn recover_interrupted_batch() {
+        let mut oram = filled_oram(16);
+        let blocks = (0..4)
+            .map(|i| oram.read_block(i).map(|block| (3 - i, block)))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+
+        // crash after the first write of a step of a sort in chunks
+        let batch = Journal::Batch(blocks.clone(), Box::new(Journal::Shuffled));
+        oram.write_journal(&batch).unwrap();
+        oram.write_block(blocks[0].0, &blocks[0].1).unwrap();
+
+        reopen(&mut oram);
+        check_contents(&mut oram);
+    }
+
+    #[test]
+    fn recover_interrupted_access() {
+        let mut oram = filled_oram(16);
+        let location = oram.locate(5).unwrap();
+        let block = oram.read_block(location).unwrap();
+        let shelter_write_index = oram.n + oram.shelter_size + oram.count;
+
+        // crash after the block left the permuted area but before it reached the shelter
+        let dummy = block.dummy_clone(oram.rng);
+        let pair = Journal::Pair(
+            location,
+            dummy.clone(),
+            shelter_write_index,
+            block,
+            Box::new(Journal::Epoch),
+        );
+        oram.write_journal(&pair).unwrap();
+        oram.write_block(location, &dummy).unwrap();
+
+        reopen(&mut oram);
+        check_contents(&mut oram);
+    }
+
+    #[test]
+    fn checkpoint_during_scan() {
+        let mut oram = filled_oram(16);
+        oram.iter().unwrap().next();
+        oram.checkpoint().unwrap();
+        assert!(matches!(
+            oram.read_journal().unwrap(),
+            Some(Journal::Rearranged)
+        ));
+
+        reopen(&mut oram);
+        check_contents(&mut oram);
+    }
+
+    #[test]
+    fn recover_interrupted_resize() {
+        for &(from, to, open_n) in &[(16, 8, 16), (16, 8, 8), (8, 16, 8), (8, 16, 16)] {
+            let mut oram = filled_oram(from);
+            for i in to..from {
+                oram.remove(i).unwrap();
+            }
+            oram.rearrange().unwrap();
+            let shelter_size = default_shelter_size(to);
+            let end = oram.capacity.max(to + 2 * shelter_size);
+            oram.write_journal(&Journal::Resized(from, to, end))
+                .unwrap();
+
+            // crash in the middle of laying out the blocks for `to`
+            oram.n = to;
+            oram.shelter_size = shelter_size;
+            oram.capacity = to + 2 * shelter_size;
+            oram.cache
+                .resize(oram.capacity as usize, Default::default());
+            let dummy = Block::new_dummy(8, oram.rng);
+            oram.write_block(oram.capacity - 1, &dummy).unwrap();
+
+            // open with either size
+            oram.n = open_n;
+            oram.shelter_size = default_shelter_size(open_n);
+            oram.capacity = open_n + 2 * oram.shelter_size;
+            oram.cache
+                .resize(oram.capacity as usize, Default::default());
+            oram.check_header().unwrap();
+            reopen(&mut oram);
+            for i in 0..open_n {
+                let expected = if i < from.min(to) {
+                    Some(vec![i as u8])
+                } else {
+                    None
+                };
+                assert_eq!(oram.get(i).unwrap(), expected);
+            }
+            for i in oram.capacity..end {
+                assert_eq!(oram.db.get(&i.to_be_bytes()).unwrap(), None);
+            }
+        }
+    }
+
+    #[test]
+    fn reject_missing_journal() {
+        let mut oram = filled_oram(16);
+        oram.db.delete(JOURNAL_KEY).unwrap();
+
+        oram.cache.iter_mut().for_each(|c| *c = Default::default());
+        assert_eq!(oram.recover(), Err(OramError::Integrity(None)));
+    }
+}
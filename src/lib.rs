@@ -19,69 +19,148 @@ use cfg_if::cfg_if;
 cfg_if! {
     if #[cfg(feature = "sgx")] {
         use sgx_tstd::{prelude::v1::*, self as std};
-        use sgx_rand::{Rng, thread_rng};
         use log_sgx::{trace};
-        use bincode_sgx::{serialize, deserialize};
+        use bincode_sgx::{serialize, deserialize, serialized_size};
         use serde_sgx::{Serialize, Deserialize};
         use serde_sgx::ser::{Serializer, SerializeTuple};
-        use serde_sgx::de::{self as de, Deserializer, Visitor,  SeqAccess};
+        use serde_sgx::de::{self as de, Deserializer, DeserializeOwned, Visitor,  SeqAccess};
         use blake2_sgx::{VarBlake2b, digest::{Input, VariableOutput}};
+        use std::sync::SgxRwLock as RwLock;
     } else if #[cfg(feature = "std")] {
-        use rand::{Rng, thread_rng};
         use log::{trace};
         use serde::{Serialize, Deserialize};
         use serde::ser::{Serializer, SerializeTuple};
-        use serde::de::{self as de, Deserializer, Visitor,  SeqAccess};
-        use bincode::{serialize, deserialize};
+        use serde::de::{self as de, Deserializer, DeserializeOwned, Visitor,  SeqAccess};
+        use bincode::{serialize, deserialize, serialized_size};
         use blake2::{VarBlake2b, digest::{Input, VariableOutput}};
-        use rand_core::RngCore;
+        use std::sync::RwLock;
     }
 }
 
 use std::boxed::Box;
+use std::cell;
 use std::collections::HashMap;
-use std::convert::TryInto;
+use std::convert;
 use std::default::Default;
 use std::fmt;
+use std::fs;
+use std::io;
 use std::ops::Range;
 use std::str;
+use std::string;
 use std::vec;
 
 mod db;
 pub mod sort;
+pub mod trace;
 
+mod archive;
+mod builder;
+mod crypto;
+mod ct;
 mod data;
+mod error;
+mod format;
+mod journal;
+mod map;
+#[cfg(feature = "parallel")]
+mod parallel;
+mod scan;
+mod shuffle;
+mod striped;
+mod typed;
+pub use builder::{
+    default_prf, default_rng, Backend, Key, OpenMode, OramBuilder, PositionLookup, PrfFn, RngFn,
+    Salt, ShuffleMode,
+};
+use ct::{ct_eq, ct_less, some_if, unwrap_if, Choice, ConditionallySelectable};
 pub use data::Data;
 use data::DataWrapper;
 use db::Database;
-type Salt = [u8; 32];
+pub use error::{OramError, Result};
+pub use format::FORMAT_VERSION;
+use journal::Journal;
+pub use map::ObliviousMap;
+pub use scan::Scan;
+use sort::Network;
+pub use striped::StripedOram;
+pub use typed::TypedOram;
 pub struct SqrtOram {
     /// Number of real blocks
-    n: usize,
+    n: u64,
     /// Number of blocks in shelter
-    shelter_size: usize,
+    shelter_size: u64,
     /// Total number of blocks in storage
-    capacity: usize,
+    capacity: u64,
     /// Salt for PRF
     salt: Salt,
     /// Database
     db: Database,
     /// Number of read/write operations executed,
-    count: usize,
+    count: u64,
+    /// Number of occupied real blocks
+    len: u64,
     /// Cache of stored blocks
     cache: Vec<BlockCache>,
+    /// Position of every block of the permuted area in the current epoch, by index
+    positions: Vec<u64>,
     /// Length of data stored in each block
     block_size: usize,
+    /// PRF deriving block tags
+    prf: PrfFn,
+    /// Source of salts and dummy data
+    rng: RngFn,
+    /// Settings not affecting the stored blocks
+    tuning: Tuning,
+    /// Blocks are rearranged for a scan and need a `permute()` before the next access
+    scanning: bool,
+}
+
+/// Settings of an ORAM that do not affect the stored blocks, see `OramBuilder`
+#[derive(Clone, Copy, Debug)]
+struct Tuning {
+    /// Batches fetch a multiple of this number of blocks
+    batch_padding: u64,
+    /// The sorting network of rearranges and sorting shuffles
+    network: Network,
+    /// How the blocks are shuffled for a new epoch
+    shuffle: ShuffleMode,
+    /// Sort in chunks, with at most this many blocks in trusted memory
+    sort_memory: Option<u64>,
+    /// How `locate()` reads `positions`
+    lookup: PositionLookup,
 }
 
 #[cfg_attr(feature = "sgx", serde(crate = "serde_sgx"))]
 #[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
 struct BlockCache {
-    tag: u32,
-    index: u32,
+    tag: u64,
+    index: u64,
+    /// Marks a block carrying no logical content.
+    ///
+    /// Kept out of band so that every `u64` value stays usable as a key.
+    dummy: bool,
+    /// Whether the block holds data written by the user
+    occupied: bool,
 }
 
-const DUMMY_INDEX: u32 = u32::MAX;
+impl ConditionallySelectable for BlockCache {
+    fn conditional_select(a: &Self, b: &Self, choice: Choice) -> Self {
+        BlockCache {
+            tag: u64::conditional_select(&a.tag, &b.tag, choice),
+            index: u64::conditional_select(&a.index, &b.index, choice),
+            dummy: bool::conditional_select(&a.dummy, &b.dummy, choice),
+            occupied: bool::conditional_select(&a.occupied, &b.occupied, choice),
+        }
+    }
+}
+
+impl BlockCache {
+    /// The key used by `SqrtOram::rearrange()`: real blocks by index, dummy blocks last
+    fn rearrange_key(&self) -> (bool, u64) {
+        (self.dummy, self.index)
+    }
+}
 
 #[cfg_attr(feature = "sgx", serde(crate = "serde_sgx"))]
 #[derive(Serialize, Deserialize, Clone)]
@@ -92,47 +171,73 @@ struct Block {
     data: DataWrapper,
 }
 
+impl ConditionallySelectable for Block {
+    fn conditional_select(a: &Self, b: &Self, choice: Choice) -> Self {
+        Block {
+            header: BlockCache::conditional_select(&a.header, &b.header, choice),
+            data: DataWrapper::conditional_select(&a.data, &b.data, choice),
+        }
+    }
+}
+
 impl Block {
-    /// Create a block with valid index (>0) or create dummy block (index = -1)
+    /// Create an unoccupied block with valid index
     ///
     /// other parameters:
+    /// - `tag`: tag derived from `index`
     /// - `size`: length of data stored
-    /// - `salt`: salt used to compute tag
-    fn new(index: u32, size: usize, salt: Salt) -> Self {
-        let mut buf = vec![0; size];
-        thread_rng().fill_bytes(&mut buf);
-        let data = DataWrapper { buf, max_len: size };
-        let tag = Self::derive_tag(index, salt);
+    /// - `rng`: source of the random data
+    fn new(index: u64, tag: u64, size: usize, rng: RngFn) -> Self {
         Block {
-            header: BlockCache { tag, index },
-            data,
+            header: BlockCache {
+                tag,
+                index,
+                dummy: false,
+                occupied: false,
+            },
+            data: Self::random_data(size, rng),
         }
     }
 
-    /// Make a dummy clone with only tag unchanged
+    /// Create a dummy block
     ///
-    /// In the clone, `index` is set to DUMMY_INDEX and `data` is randomized.
-    fn dummy_clone(&self) -> Self {
-        let mut buf = vec![0; self.data.max_len];
-        thread_rng().fill_bytes(&mut buf);
-        let data = DataWrapper {
-            buf,
-            max_len: self.data.max_len,
-        };
+    /// Dummy blocks are placed in the shelter, so their tag is never looked up.
+    fn new_dummy(size: usize, rng: RngFn) -> Self {
         Block {
             header: BlockCache {
-                tag: self.header.tag,
-                index: DUMMY_INDEX,
+                tag: 0,
+                index: 0,
+                dummy: true,
+                occupied: false,
             },
-            data,
+            data: Self::random_data(size, rng),
         }
     }
 
-    fn derive_tag(index: u32, salt: Salt) -> u32 {
-        let mut hasher = VarBlake2b::new_keyed(&salt, 4);
-        hasher.input(index.to_be_bytes());
-        let hash = hasher.vec_result();
-        u32::from_be_bytes(hash[0..4].try_into().expect("slice to array"))
+    fn random_data(size: usize, rng: RngFn) -> DataWrapper {
+        let mut buf = vec![0; size];
+        rng(&mut buf);
+        DataWrapper { buf, max_len: size }
+    }
+
+    /// Decode the block stored at position `k`, if any
+    fn decode(k: u64, data: Option<Vec<u8>>, block_size: usize) -> Result<Self> {
+        let data = data.ok_or(OramError::Integrity(Some(k)))?;
+        let block = format::decode_block(&data).ok_or(OramError::Corruption(k))?;
+        if block.data.max_len != block_size {
+            // a corrupt block as `max_len` is incorrect
+            return Err(OramError::Corruption(k));
+        }
+        Ok(block)
+    }
+
+    /// Make a dummy clone with only tag unchanged
+    ///
+    /// In the clone, the block is marked as dummy and `data` is randomized.
+    fn dummy_clone(&self, rng: RngFn) -> Self {
+        let mut block = Self::new_dummy(self.data.max_len, rng);
+        block.header.tag = self.header.tag;
+        block
     }
 }
 
@@ -141,8 +246,10 @@ impl SqrtOram {
     ///
     /// - `n`: number of real blocks
     /// - `block_size`: size of each blocks in bytes
-    pub fn new(n: usize, block_size: usize) -> Self {
-        Self::create(n, block_size, None)
+    ///
+    /// For other options, see `OramBuilder`.
+    pub fn new(n: u64, block_size: usize) -> Result<Self> {
+        OramBuilder::new(n, block_size).build()
     }
 
     /// Open an existing or create a new SqrtORAM on disk.
@@ -150,267 +257,688 @@ impl SqrtOram {
     /// - `name`: name of the storage; name of the data directory on file system
     /// - `n`: number of real blocks
     /// - `block_size`: size of each blocks in bytes
-    pub fn open(name: &'static str, n: usize, block_size: usize) -> Self {
-        Self::create(n, block_size, Some(name))
+    ///
+    /// For other options, see `OramBuilder`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the storage cannot be opened or an existing store does not
+    /// pass the integrity checks.
+    pub fn open(name: &str, n: u64, block_size: usize) -> Result<Self> {
+        OramBuilder::new(n, block_size)
+            .backend(Backend::persistent())
+            .path(name)
+            .build()
     }
 
-    /// An internal method for creating SqrtOram
-    fn create(n: usize, block_size: usize, name: Option<&'static str>) -> Self {
-        let shelter_size = (n as f64).sqrt() as usize;
+    /// An internal method for creating SqrtOram, see `OramBuilder::build()`
+    fn create(
+        n: u64,
+        shelter_size: u64,
+        block_size: usize,
+        db: Database,
+        prf: PrfFn,
+        rng: RngFn,
+        tuning: Tuning,
+    ) -> Result<Self> {
         let capacity = n + 2 * shelter_size;
-        let salt = Self::generate_salt();
-        let db = Self::create_db(name);
-        let cache = vec![Default::default(); capacity];
+        let cache = vec![Default::default(); capacity as usize];
         let existed = db.existed();
 
         let mut oram = SqrtOram {
             n,
             shelter_size,
             capacity,
-            salt,
+            salt: Default::default(),
             db,
             count: 0,
+            len: 0,
             cache,
+            positions: Vec::new(),
             block_size,
+            prf,
+            rng,
+            tuning,
+            scanning: false,
         };
+        oram.salt = oram.generate_salt();
 
         if existed {
-            // If this is a re-open, recalculate the hash
-            oram.warm_up_cache();
-            oram.rehash();
+            // If this is a re-open, finish what was interrupted and start a new epoch
+            oram.check_header()?;
+            oram.recover()?;
         } else {
-            // If DB is opened for the first time, initialize the blocks
-            oram.init_blocks();
+            // If DB is opened for the first time, initialize the blocks. The
+            // journal comes first, so a store with a header always has one.
+            oram.write_journal(&Journal::Init)?;
+            oram.write_header()?;
+            oram.init_blocks()?;
         }
 
-        oram.shuffle();
-        oram
+        oram.permute()?;
+        Ok(oram)
     }
 
-    fn warm_up_cache(&mut self) {
+    fn warm_up_cache(&mut self) -> Result<()> {
         for i in 0..self.capacity {
-            let data = self.db.get(&(i as u32).to_be_bytes()).expect("get block");
-            let block: Block = deserialize(&data[..]).expect("deserialize block");
-            self.cache[i] = block.header;
+            let block = self.load_block(i)?;
+            self.cache[i as usize] = block.header;
         }
+        Ok(())
     }
 
-    fn init_blocks(&mut self) {
+    fn init_blocks(&mut self) -> Result<()> {
         for i in 0..self.capacity {
-            let mut block_index = 0 as u32;
-            if self.real_range().contains(&i) || self.dummy_range().contains(&i) {
-                block_index = i as u32;
-            } else if self.shelter_range().contains(&i) {
-                block_index = DUMMY_INDEX;
-            }
-            let block = Block::new(block_index, self.block_size, self.salt);
-            self.write_block(i as u32, &block);
+            let block = if self.real_range().contains(&i) || self.dummy_range().contains(&i) {
+                Block::new(i, self.tag(i), self.block_size, self.rng)
+            } else {
+                Block::new_dummy(self.block_size, self.rng)
+            };
+            self.write_block(i, &block)?;
         }
+        Ok(())
+    }
+
+    /// Load and decode the block at `k` without checking it against the cache
+    fn load_block(&mut self, k: u64) -> Result<Block> {
+        let data = self.db.get(&k.to_be_bytes())?;
+        Block::decode(k, data, self.block_size)
     }
 
-    fn read_block(&mut self, k: u32) -> Block {
+    fn read_block(&mut self, k: u64) -> Result<Block> {
         trace!("read_block(key={})", k);
-        let data = self.db.get(&k.to_be_bytes()).expect("get block");
-        let block: Block = deserialize(&data[..]).expect("deserialize block");
-        assert_eq!(
-            block.data.max_len, self.block_size,
-            "a corrupt block as `max_len` is incorrect"
-        );
-        assert_eq!(self.cache[k as usize], block.header);
-        block
+        let block = self.load_block(k)?;
+        if self.cache[k as usize] != block.header {
+            return Err(OramError::Integrity(Some(k)));
+        }
+        Ok(block)
     }
 
-    fn write_block(&mut self, k: u32, v: &Block) {
+    fn write_block(&mut self, k: u64, v: &Block) -> Result<()> {
         trace!("write_block(key={})", k);
-        self.cache[k as usize] = v.header.clone();
         self.db
-            .put(&k.to_be_bytes(), &serialize(v).expect("serialize block"));
+            .put(&k.to_be_bytes(), &serialize(v).expect("serialize block"))?;
+        self.cache[k as usize] = v.header.clone();
+        Ok(())
     }
 
-    fn real_range(&self) -> Range<usize> {
+    /// Find the position of block `k` in the permuted area
+    ///
+    /// The position is then read from storage, so checking the cache entry at
+    /// that position reveals nothing more.
+    fn locate(&self, k: u64) -> Result<u64> {
+        let position = match self.tuning.lookup {
+            PositionLookup::Direct => self.positions.get(k as usize).copied(),
+            PositionLookup::Oblivious => {
+                let mut position = 0;
+                let mut found = Choice::from(false);
+                for (j, i) in self.positions.iter().enumerate() {
+                    let hit = ct_eq(j as u64, k);
+                    position.conditional_assign(i, hit);
+                    found = found | hit;
+                }
+                // keys are checked to be in range, so this is not secret
+                Some(position).filter(|_| found.unwrap_u8() == 1)
+            }
+        };
+        match position {
+            Some(i) if self.cache[i as usize].index == k && !self.cache[i as usize].dummy => Ok(i),
+            _ => Err(OramError::Integrity(None)),
+        }
+    }
+
+    /// Record the positions of the blocks in the permuted area, see `locate()`
+    ///
+    /// Dummy blocks are left out. With the oblivious lookup, the table is built
+    /// by sorting `(index, position)` pairs with a sorting network instead of
+    /// writing to the slot of each index, which would reveal the permutation.
+    fn map_positions(&mut self) {
+        let permuted = self.dummy_range().end as usize;
+        if let PositionLookup::Oblivious = self.tuning.lookup {
+            let mut pairs = self.cache[..permuted]
+                .iter()
+                .enumerate()
+                .map(|(i, c)| {
+                    let index = u64::conditional_select(&c.index, &u64::MAX, Choice::from(c.dummy));
+                    (index, i as u64)
+                })
+                .collect::<Vec<_>>();
+            sort::sort_in_memory(self.tuning.network, &mut pairs, |x, y| {
+                ct_less(x.0, y.0).unwrap_u8() == 1
+            });
+            self.positions = pairs.into_iter().map(|(_, i)| i).collect();
+            return;
+        }
+        self.positions.clear();
+        self.positions.resize(permuted, 0);
+        for (i, c) in self.cache[..permuted].iter().enumerate() {
+            if let Some(position) = self
+                .positions
+                .get_mut(c.index as usize)
+                .filter(|_| !c.dummy)
+            {
+                *position = i as u64;
+            }
+        }
+    }
+
+    fn real_range(&self) -> Range<u64> {
         0..self.n
     }
 
-    fn dummy_range(&self) -> Range<usize> {
+    fn dummy_range(&self) -> Range<u64> {
         self.n..self.n + self.shelter_size
     }
 
-    fn shelter_range(&self) -> Range<usize> {
+    fn shelter_range(&self) -> Range<u64> {
         self.n + self.shelter_size..self.capacity
     }
 
-    #[cfg(feature = "std")]
-    fn generate_salt() -> Salt {
-        rand::thread_rng().gen::<Salt>()
+    fn generate_salt(&self) -> Salt {
+        let mut salt = Salt::default();
+        (self.rng)(&mut salt);
+        salt
     }
 
-    #[cfg(feature = "sgx")]
-    fn generate_salt() -> Salt {
-        sgx_rand::thread_rng().gen::<Salt>()
+    /// Derive the tag of the block at position `i` with the current salt
+    fn tag(&self, i: u64) -> u64 {
+        (self.prf)(&self.salt, i)
     }
 
-    #[cfg(feature = "std")]
-    fn create_db(name: Option<&'static str>) -> Database {
-        match name {
-            Some(db_name) => Database::open(db_name, db::Options::leveldb()),
-            None => Database::open("in-memory", db::Options::in_memory()),
-        }
+    /// Number of logical blocks; valid keys are `0..num_blocks()`
+    pub fn num_blocks(&self) -> u64 {
+        self.n
     }
 
-    #[cfg(feature = "sgx")]
-    fn create_db(name: Option<&'static str>) -> Database {
-        match name {
-            Some(db_name) => Database::open(db_name, db::Options::sgxfs()),
-            None => Database::open("in-memory", db::Options::in_memory()),
-        }
+    /// Maximum length of data stored in a block
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Number of keys holding data
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether no key holds data
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
     }
 
     /// Store data `v` at key `k`
     ///
     /// `v` has a capacity limit up to `self.block_size`.
     ///
-    /// # Panic
+    /// # Errors
     ///
-    /// panic when `v.len()` is greater than self.block_size
-    pub fn put(&mut self, k: u32, v: Data) {
-        assert!(
-            v.len() <= self.block_size,
-            "`v.len()` should be less than block_size"
-        );
-        self.access(
-            k,
-            Some(DataWrapper {
-                buf: v,
-                max_len: self.block_size,
-            }),
-        );
+    /// - `OramError::Capacity` when `v.len()` is greater than self.block_size
+    /// - `OramError::KeyOutOfRange` when `k` is not less than the number of real blocks
+    pub fn put(&mut self, k: u64, v: Data) -> Result<()> {
+        if v.len() > self.block_size {
+            return Err(OramError::Capacity {
+                len: v.len(),
+                block_size: self.block_size,
+            });
+        }
+        self.access(k, |content| *content = Some(v))?;
+        Ok(())
     }
 
     /// Similar to HashMap::get()
-    pub fn get(&mut self, k: u32) -> Option<Data> {
-        self.access(k, None).map(|d| d.buf)
+    ///
+    /// Returns `None` if nothing was stored at `k` or it was removed.
+    pub fn get(&mut self, k: u64) -> Result<Option<Data>> {
+        self.access(k, |_| {})
     }
 
-    /// If write is None, access() will run read operation, otherwise write.
-    fn access(&mut self, k: u32, write: Option<DataWrapper>) -> Option<DataWrapper> {
-        let mut found_in_shelter = false;
-        let mut found_block = Block::new(DUMMY_INDEX, self.block_size, self.salt);
+    /// Clear key `k`, returning its data
+    ///
+    /// The access looks the same as `get()` and `put()` to the host.
+    pub fn remove(&mut self, k: u64) -> Result<Option<Data>> {
+        let mut removed = None;
+        self.access(k, |content| removed = content.take())?;
+        Ok(removed)
+    }
 
-        for i in self.shelter_range() {
-            trace!("accessing block {} in shelter", i);
-            let block = self.read_block(i as u32);
-            if !found_in_shelter && self.cache[i].index == k {
-                found_in_shelter = true;
-                found_block = block.clone();
+    /// Modify the data at key `k` in place with a single access
+    ///
+    /// `f` gets the stored data, or `None` if nothing is stored at `k`, and its
+    /// result is returned. The access looks the same as `get()` to the host.
+    ///
+    /// # Errors
+    ///
+    /// `OramError::Capacity` if `f` makes the data longer than `self.block_size`.
+    /// The data is left unchanged then.
+    pub fn update<F, R>(&mut self, k: u64, f: F) -> Result<R>
+    where
+        F: FnOnce(Option<&mut Data>) -> R,
+    {
+        let block_size = self.block_size;
+        let mut result = None;
+        let mut too_long = None;
+        self.access(k, |content| {
+            let old = content.clone();
+            result = Some(f(content.as_mut()));
+            let len = content.as_ref().map_or(0, |data| data.len());
+            if len > block_size {
+                too_long = Some(len);
+                *content = old;
             }
-            self.write_block(i as u32, &block);
+        })?;
+        match too_long {
+            Some(len) => Err(OramError::Capacity { len, block_size }),
+            None => Ok(result.expect("closure called by access()")),
         }
+    }
 
-        if found_in_shelter {
-            let seek = Block::derive_tag((self.n + self.count) as u32, self.salt);
-            let location = match self.cache[0..self.capacity - self.shelter_size]
-                .binary_search_by(|c| c.tag.cmp(&seek))
-            {
-                Ok(i) => i as u32,
-                //TODO: handle the error
-                Err(_) => panic!("Binary search should be successful"),
-            };
-            let block = self.read_block(location);
-            self.write_block(location, &block);
-        } else {
-            let seek = Block::derive_tag(k as u32, self.salt);
-            let location = match self.cache[0..self.capacity - self.shelter_size]
-                .binary_search_by(|c| c.tag.cmp(&seek))
-            {
-                Ok(i) => i as u32,
-                //TODO: handle the error
-                Err(_) => panic!("Binary search should be successful"),
-            };
-            found_block = self.read_block(location);
-            self.write_block(location, &found_block.dummy_clone());
+    /// Read the data of many keys at once
+    ///
+    /// The shelter is scanned once for every round of up to `shelter_size` keys
+    /// instead of once per key. Duplicate keys are only fetched once but padded
+    /// with dummy fetches, so the host learns only `keys.len()` rounded up to
+    /// the batch padding (see `OramBuilder::batch_padding()`).
+    pub fn get_many(&mut self, keys: &[u64]) -> Result<Vec<Option<Data>>> {
+        let (distinct, positions) = distinct_keys(keys.iter().copied());
+        let fetches = self.padded_batch_len(keys.len());
+        let contents = self.access_many(&distinct, fetches, |_, _| {})?;
+        Ok(positions.into_iter().map(|i| contents[i].clone()).collect())
+    }
+
+    /// Store many key-value pairs at once
+    ///
+    /// When a key appears more than once, the last value is stored. See
+    /// `get_many()` for what the host learns.
+    ///
+    /// # Errors
+    ///
+    /// Besides the errors of `put()`, nothing is stored if any data is too long.
+    pub fn put_many(&mut self, items: Vec<(u64, Data)>) -> Result<()> {
+        if let Some((_, v)) = items.iter().find(|(_, v)| v.len() > self.block_size) {
+            return Err(OramError::Capacity {
+                len: v.len(),
+                block_size: self.block_size,
+            });
         }
+        let fetches = self.padded_batch_len(items.len());
+        let (distinct, positions) = distinct_keys(items.iter().map(|(k, _)| *k));
+        let mut values = vec![None; distinct.len()];
+        for ((_, v), i) in items.into_iter().zip(positions) {
+            values[i] = Some(v);
+        }
+        self.access_many(&distinct, fetches, |i, content| *content = values[i].take())?;
+        Ok(())
+    }
 
-        let shelter_write_index = (self.n + self.shelter_size + self.count) as u32;
-        let mut is_write = false;
-        if found_in_shelter {
-            self.write_block(
-                shelter_write_index,
-                &Block::new(DUMMY_INDEX, self.block_size, self.salt),
-            )
-        } else if let Some(data) = write {
-            is_write = true;
-            found_block.data = data;
-            self.write_block(shelter_write_index, &found_block);
-        } else {
-            self.write_block(shelter_write_index, &found_block);
+    fn padded_batch_len(&self, len: usize) -> u64 {
+        let len = len as u64;
+        match len % self.tuning.batch_padding {
+            0 => len,
+            rem => len + self.tuning.batch_padding - rem,
         }
-        self.count += 1;
-        if self.count == self.shelter_size {
-            self.rearrange();
-            self.rehash();
-            self.shuffle();
-            self.count = 0;
+    }
+
+    /// Access block `k`, running `f` on its content, and return the new content
+    ///
+    /// Reads and writes go through the same steps, `f` decides what is written back.
+    fn access<F>(&mut self, k: u64, f: F) -> Result<Option<Data>>
+    where
+        F: FnOnce(&mut Option<Data>),
+    {
+        let mut f = Some(f);
+        let mut contents = self.access_many(&[k], 1, |_, content| {
+            (f.take().expect("applied once"))(content)
+        })?;
+        Ok(contents.pop().expect("one key accessed"))
+    }
+
+    /// Access the distinct keys `keys` with `fetches` fetches from the permuted area,
+    /// running `f(i, content)` on the content of `keys[i]`, and return the new contents
+    ///
+    /// Every round reads the shelter, then fetches as many blocks as there are
+    /// free shelter slots, then writes the shelter back. Fetches beyond
+    /// `keys.len()`, and those of keys already in the shelter, read dummy blocks
+    /// instead.
+    ///
+    /// Whether a key is in the shelter is only ever used through constant-time
+    /// selects: every fetch reads one block and writes two, every shelter block
+    /// is compared with every key of the round, and `f` is run once per key on a
+    /// block selected from the shelter or the fetch.
+    fn access_many<F>(&mut self, keys: &[u64], fetches: u64, mut f: F) -> Result<Vec<Option<Data>>>
+    where
+        F: FnMut(usize, &mut Option<Data>),
+    {
+        debug_assert!(keys.len() as u64 <= fetches);
+        self.finish_scan()?;
+        if let Some(&k) = keys.iter().find(|&&k| !self.real_range().contains(&k)) {
+            return Err(OramError::KeyOutOfRange { key: k, n: self.n });
         }
 
-        if is_write {
-            None
-        } else {
-            Some(found_block.data)
+        let mut contents = vec![None; keys.len()];
+        let mut done = 0;
+        while done < fetches {
+            let round = (fetches - done).min(self.shelter_size - self.count);
+            let start = (done as usize).min(keys.len());
+            let end = ((done + round) as usize).min(keys.len());
+            let wanted = &keys[start..end];
+            let shelter = self.shelter_range();
+
+            // the block of every wanted key found in the shelter, and its position
+            let mut found = vec![Choice::from(false); wanted.len()];
+            let mut slots = vec![0; wanted.len()];
+            let mut held = vec![Block::new_dummy(self.block_size, self.rng); wanted.len()];
+            let mut sheltered = Vec::with_capacity(self.shelter_size as usize);
+            for i in shelter.clone() {
+                trace!("accessing block {} in shelter", i);
+                let block = self.read_block(i)?;
+                let header = &self.cache[i as usize];
+                for (j, &k) in wanted.iter().enumerate() {
+                    let hit = ct_eq(header.index, k) & !Choice::from(header.dummy);
+                    found[j] = found[j] | hit;
+                    slots[j].conditional_assign(&i, hit);
+                    held[j].conditional_assign(&block, hit);
+                }
+                sheltered.push(block);
+            }
+
+            for fetch in done..done + round {
+                let j = fetch as usize;
+                let shelter_write_index = self.n + self.shelter_size + self.count;
+                // fetches past the keys are dummy fetches, which the batch padding
+                // already makes public
+                let (k, hit) = match j.checked_sub(start).filter(|&w| w < wanted.len()) {
+                    Some(w) => (wanted[w], found[w]),
+                    None => (0, Choice::from(true)),
+                };
+                // a key found in the shelter fetches the next dummy block, which
+                // stays in place
+                let location =
+                    self.locate(u64::conditional_select(&k, &(self.n + self.count), hit))?;
+                let fetched = self.read_block(location)?;
+                let kept = Block::conditional_select(&fetched.dummy_clone(self.rng), &fetched, hit);
+                let dummy = Block::new_dummy(self.block_size, self.rng);
+                let mut moved = Block::conditional_select(&fetched, &dummy, hit);
+                if j < end {
+                    let w = j - start;
+                    let mut block = Block::conditional_select(&fetched, &held[w], hit);
+                    contents[j] = self.apply(&mut block, |content| f(j, content));
+                    moved = Block::conditional_select(&block, &dummy, hit);
+                    held[w] = block;
+                }
+                // Written as a pair in every case, so that the journal does not
+                // tell dummy fetches apart
+                self.write_pair(location, &kept, shelter_write_index, &moved, Journal::Epoch)?;
+                sheltered[(shelter_write_index - shelter.start) as usize] = moved;
+                self.count += 1;
+            }
+
+            // A sheltered block is updated in place
+            for (i, mut block) in shelter.zip(sheltered) {
+                for (j, held) in held.iter().enumerate() {
+                    block.conditional_assign(held, found[j] & ct_eq(slots[j], i));
+                }
+                self.write_block(i, &block)?;
+            }
+
+            if self.count == self.shelter_size {
+                self.rearrange()?;
+                self.permute()?;
+            }
+            done += round;
+        }
+
+        Ok(contents)
+    }
+
+    /// Run `f` on the content of a real block, keeping `len` up to date
+    ///
+    /// The content is moved in and out of `Option`s with constant-time selects
+    /// of data padded to the block size, so nothing but `f` itself branches on
+    /// the content.
+    fn apply<F>(&mut self, block: &mut Block, f: F) -> Option<Data>
+    where
+        F: FnOnce(&mut Option<Data>),
+    {
+        let max_len = block.data.max_len;
+        let empty = DataWrapper {
+            buf: Vec::new(),
+            max_len,
+        };
+        let was_occupied = Choice::from(block.header.occupied);
+        let stored = DataWrapper::conditional_select(&empty, &block.data, was_occupied);
+        let mut content = some_if(stored.buf, was_occupied);
+        f(&mut content);
+
+        let occupied = Choice::from(content.is_some());
+        let written = DataWrapper {
+            buf: unwrap_if(content, occupied),
+            max_len,
+        };
+        block.data = DataWrapper::conditional_select(&empty, &written, occupied);
+        block.header.occupied = bool::conditional_select(&false, &true, occupied);
+        self.len = self.len + u64::from(occupied.unwrap_u8()) - u64::from(was_occupied.unwrap_u8());
+        // a copy of the whole block size, whatever the length of the data
+        let mut copy = Vec::with_capacity(max_len);
+        copy.extend_from_slice(&block.data.buf);
+        some_if(copy, occupied)
+    }
+
+    /// Change the number of real blocks to `new_n`, keeping all stored keys
+    ///
+    /// The shelter size becomes `sqrt(new_n)`. The blocks are rearranged once
+    /// with an oblivious sort, which moves the keys to the front in order. The
+    /// positions past the keys kept are then laid out afresh, storage past the
+    /// new capacity is deleted, and the new layout is rehashed and shuffled.
+    ///
+    /// The resize is journaled once the blocks are rearranged. A store
+    /// interrupted before that opens with the old `n`; afterwards it opens with
+    /// either `n`, and the resize is finished or rolled back accordingly.
+    ///
+    /// # Errors
+    ///
+    /// `OramError::InvalidConfig` if `new_n` is zero or a key beyond `new_n`
+    /// holds data. Nothing is changed then.
+    pub fn resize(&mut self, new_n: u64) -> Result<()> {
+        if new_n == 0 {
+            return Err(OramError::InvalidConfig(
+                "the number of blocks must be positive".into(),
+            ));
+        }
+        if !self.scanning {
+            self.rearrange()?;
+            // the blocks stay rearranged if a key beyond `new_n` holds data
+            self.scanning = true;
+        }
+        // After rearranging, block `i` is at position `i` for `i < n + shelter_size`
+        for i in new_n..self.n {
+            if self.read_block(i)?.header.occupied {
+                return Err(OramError::InvalidConfig(
+                    "keys beyond the new size hold data".into(),
+                ));
+            }
+        }
+
+        let kept = self.n.min(new_n);
+        let shelter_size = builder::default_shelter_size(new_n);
+        let end = self.capacity.max(new_n + 2 * shelter_size);
+        self.write_journal(&Journal::Resized(self.n, new_n, end))?;
+        self.n = new_n;
+        self.shelter_size = shelter_size;
+        self.capacity = new_n + 2 * shelter_size;
+        self.lay_out(kept, end)?;
+        self.scanning = false;
+        self.permute()
+    }
+
+    /// Lay out rearranged blocks for the current `n` and shelter size
+    ///
+    /// Blocks before `kept` stay, the other positions up to the capacity are
+    /// written afresh and positions from the capacity to `end` are deleted.
+    /// Writing the header completes the layout.
+    pub(crate) fn lay_out(&mut self, kept: u64, end: u64) -> Result<()> {
+        self.cache
+            .resize(self.capacity as usize, Default::default());
+        for i in kept..self.capacity {
+            let block = if i < self.dummy_range().end {
+                Block::new(i, self.tag(i), self.block_size, self.rng)
+            } else {
+                Block::new_dummy(self.block_size, self.rng)
+            };
+            self.write_block(i, &block)?;
+        }
+        for i in self.capacity..end {
+            self.db.delete(&i.to_be_bytes())?;
+        }
+        self.write_header()
+    }
+
+    /// Start a new epoch from rearranged blocks: shuffle them and record their positions
+    fn permute(&mut self) -> Result<()> {
+        match self.tuning.shuffle {
+            ShuffleMode::SortByTag => {
+                self.rehash()?;
+                self.shuffle()?;
+            }
+            ShuffleMode::Melbourne => {
+                self.salt = self.generate_salt();
+                self.melbourne_shuffle()?;
+            }
+        }
+        self.map_positions();
+        self.count = 0;
+        self.write_journal(&Journal::Epoch)
+    }
+
+    /// Make the store durable
+    ///
+    /// Every operation leaves the store recoverable, so this only has to flush
+    /// the backend. Unlike dropping the ORAM, it reports errors. Reopening the
+    /// store always starts a new epoch with a new salt.
+    pub fn checkpoint(&mut self) -> Result<()> {
+        if self.scanning {
+            self.write_journal(&Journal::Rearranged)?;
         }
+        self.db.flush()
+    }
+
+    /// Checkpoint and close the store
+    ///
+    /// Dropping the ORAM does no work, use `close()` to learn if the last
+    /// writes reached the storage.
+    pub fn close(mut self) -> Result<()> {
+        self.checkpoint()
     }
 
     /// Rotate the salt value and re-derive the tag value for each block
     ///
     /// TODO: find a better name or move the code
-    fn rehash(&mut self) {
-        self.salt = Self::generate_salt();
+    fn rehash(&mut self) -> Result<()> {
+        self.salt = self.generate_salt();
         for i in 0..self.dummy_range().end {
-            let mut block = self.read_block(i as u32);
-            block.header.tag = Block::derive_tag(i as u32, self.salt);
-            self.write_block(i as u32, &block);
+            let mut block = self.read_block(i)?;
+            block.header.tag = self.tag(i);
+            self.write_block(i, &block)?;
         }
+        Ok(())
     }
 
     /// Shuffle real and dummy blocks
     ///
     /// Internally it sorts real and dummy blocks accroding to their tag.
-    fn shuffle(&mut self) {
-        sort::odd_even_mergesort(
-            0..self.dummy_range().end,
-            |x: &Block, y: &Block| x.header.tag < y.header.tag,
-            |i, w| match w {
-                Some(x) => {
-                    self.write_block(i as u32, x);
-                    None
-                }
-                None => Some(self.read_block(i as u32)),
-            },
-        )
+    fn shuffle(&mut self) -> Result<()> {
+        self.sort_blocks(0..self.dummy_range().end, |x, y| {
+            x.header.tag < y.header.tag
+        })
     }
 
     /// Rearrange the blocks so that real blocks are sorted into `Self::real_range()`.
     ///
     /// Internally it sorts all blocks accroding to the original index. Real blocks
-    /// will have valid index while dummy and shelter blocks have DUMMY_INDEX.
-    fn rearrange(&mut self) {
-        sort::odd_even_mergesort(
-            0..self.capacity,
-            |x: &Block, y: &Block| x.header.index < y.header.index,
-            |i, w| match w {
-                Some(x) => {
-                    self.write_block(i as u32, x);
-                    None
+    /// will have valid index while dummy and shelter blocks are marked as dummy
+    /// and sorted to the end.
+    fn rearrange(&mut self) -> Result<()> {
+        self.sort_blocks(0..self.capacity, |x, y| {
+            x.header.rearrange_key() < y.header.rearrange_key()
+        })
+    }
+
+    /// Sort the blocks in `range`, journaling every swap
+    ///
+    /// The sorting network writes both blocks of every comparator in a row, so
+    /// the first write is held back until the second one arrives. A sort in
+    /// chunks writes back all blocks it read in a step, and these writes are
+    /// held back until the last one.
+    fn sort_blocks<C>(&mut self, range: Range<u64>, cmp: C) -> Result<()>
+    where
+        C: Fn(&Block, &Block) -> bool + Sync,
+    {
+        if let Some(memory) = self.tuning.sort_memory {
+            let mut reads = 0;
+            let mut batch = Vec::new();
+            return sort::try_sort_in_chunks(
+                self.tuning.network,
+                range,
+                memory,
+                cmp,
+                |i, w| match w {
+                    Some(x) => {
+                        batch.push((i, x.clone()));
+                        if batch.len() == reads {
+                            reads = 0;
+                            self.write_batch(&batch, Journal::Shuffled)?;
+                            batch.clear();
+                        }
+                        Ok(None)
+                    }
+                    None => {
+                        reads += 1;
+                        self.read_block(i).map(Some)
+                    }
+                },
+            );
+        }
+
+        #[cfg(feature = "parallel")]
+        {
+            if let Some(storage) = self.db.shared() {
+                let sort = parallel::BlockSort {
+                    storage: &*storage,
+                    cache: &mut self.cache,
+                    block_size: self.block_size,
+                };
+                return sort.sort(self.tuning.network, range, cmp);
+            }
+        }
+
+        let mut pending = None;
+        sort::try_sort_with(self.tuning.network, range, cmp, |i, w| match w {
+            Some(x) => match pending.take() {
+                None => {
+                    pending = Some((i, x.clone()));
+                    Ok(None)
                 }
-                None => Some(self.read_block(i as u32)),
+                Some((j, y)) => self
+                    .write_pair(j, &y, i, x, Journal::Shuffled)
+                    .map(|_| None),
             },
-        )
+            None => self.read_block(i).map(Some),
+        })
     }
 }
 
-impl Drop for SqrtOram {
-    fn drop(&mut self) {
-        self.rearrange();
-    }
+/// Deduplicate `keys`, returning the distinct keys in order of first appearance
+/// and the position of every key in them
+fn distinct_keys<I: Iterator<Item = u64>>(keys: I) -> (Vec<u64>, Vec<usize>) {
+    let mut distinct = Vec::new();
+    let mut seen = HashMap::new();
+    let positions = keys
+        .map(|k| {
+            *seen.entry(k).or_insert_with(|| {
+                distinct.push(k);
+                distinct.len() - 1
+            })
+        })
+        .collect();
+    (distinct, positions)
 }
 
 // do all the unitest with std libraries
@@ -418,7 +946,6 @@ impl Drop for SqrtOram {
 mod tests {
     use super::*;
     use env_logger::Env;
-    use hex;
     use log::{debug, info};
     use std::fmt;
     use std::fs;
@@ -431,7 +958,7 @@ mod tests {
                 formatter,
                 "Block {{ header: {:?}, data: {}... }}",
                 self.header,
-                hex::encode(self.data.buf[0..16].to_vec())
+                hex::encode(&self.data.buf[..self.data.buf.len().min(16)])
             )
         }
     }
@@ -446,17 +973,18 @@ mod tests {
     fn initialization() {
         init_logger();
 
-        let n = 16 as usize;
-        let mut oram = SqrtOram::new(n, TEST_BLOCK_SIZE);
-        oram.init_blocks();
+        let n: u64 = 16;
+        let mut oram = SqrtOram::new(n, TEST_BLOCK_SIZE).unwrap();
+        oram.init_blocks().unwrap();
 
         for i in 0..oram.capacity {
-            let block = oram.read_block(i as u32);
+            let block = oram.read_block(i).unwrap();
             info!("Block {} = {:?}", i, &block);
             if oram.real_range().contains(&i) || oram.dummy_range().contains(&i) {
-                assert_eq!(block.header.index, i as u32);
+                assert!(!block.header.dummy);
+                assert_eq!(block.header.index, i);
             } else if oram.shelter_range().contains(&i) {
-                assert_eq!(block.header.index, DUMMY_INDEX);
+                assert!(block.header.dummy);
             } else {
                 panic!(
                     "index {} should be in either real_range(), dummy_range() or shelter_range()",
@@ -466,24 +994,30 @@ mod tests {
         }
     }
 
+    #[test]
+    fn tag_covers_whole_index() {
+        let salt = [1; 32];
+        assert_ne!(default_prf(&salt, 1), default_prf(&salt, 1 + (1 << 32)));
+    }
+
     fn dump_blocks(oram: &mut SqrtOram) {
         for i in 0..oram.capacity {
-            debug!("{:?}", oram.read_block(i as u32));
+            debug!("{:?}", oram.read_block(i).unwrap());
         }
     }
 
     #[test]
     fn single_write_and_read() {
         init_logger();
-        let mut oram = SqrtOram::new(16, TEST_BLOCK_SIZE);
+        let mut oram = SqrtOram::new(16, TEST_BLOCK_SIZE).unwrap();
 
         dump_blocks(&mut oram);
-        let key = 15 as u32;
+        let key: u64 = 15;
         let mut data = [0u8; TEST_BLOCK_SIZE];
-        data[0..4].copy_from_slice(&key.to_le_bytes());
-        oram.put(key, data.to_vec());
+        data[0..8].copy_from_slice(&key.to_le_bytes());
+        oram.put(key, data.to_vec()).unwrap();
         dump_blocks(&mut oram);
-        let read_data = oram.get(key).unwrap();
+        let read_data = oram.get(key).unwrap().unwrap();
         assert_eq!(read_data[..], data[..]);
     }
 
@@ -491,44 +1025,341 @@ mod tests {
     fn basic_test() {
         init_logger();
 
-        let n = 8 as usize;
-        let mut oram = SqrtOram::new(n, TEST_BLOCK_SIZE);
+        let n: u64 = 8;
+        let mut oram = SqrtOram::new(n, TEST_BLOCK_SIZE).unwrap();
 
         for i in 0..n {
             let mut data = [0u8; TEST_BLOCK_SIZE];
             data[0..8].copy_from_slice(&i.to_le_bytes());
             assert_eq!(oram.count, i % oram.shelter_size);
-            oram.put(i as u32, data.to_vec());
+            oram.put(i, data.to_vec()).unwrap();
         }
 
         for i in 0..n {
             let mut data = [0u8; TEST_BLOCK_SIZE];
             data[0..8].copy_from_slice(&i.to_le_bytes());
             assert_eq!(oram.count % oram.shelter_size, (n + i) % oram.shelter_size);
-            assert_eq!(&data[..], &oram.get(i as u32).unwrap()[..]);
+            assert_eq!(&data[..], &oram.get(i).unwrap().unwrap()[..]);
         }
     }
 
     #[test]
-    #[should_panic]
     fn put_lengthy_data() {
         init_logger();
 
-        let n = 8 as usize;
-        let mut oram = SqrtOram::new(n, TEST_BLOCK_SIZE);
+        let n: u64 = 8;
+        let mut oram = SqrtOram::new(n, TEST_BLOCK_SIZE).unwrap();
+
+        assert_eq!(
+            oram.put(0, vec![0; TEST_BLOCK_SIZE + 1]),
+            Err(OramError::Capacity {
+                len: TEST_BLOCK_SIZE + 1,
+                block_size: TEST_BLOCK_SIZE
+            })
+        );
+    }
+
+    #[test]
+    fn overwrite_sheltered_block() {
+        init_logger();
+
+        let n: u64 = 16;
+        let mut oram = SqrtOram::new(n, TEST_BLOCK_SIZE).unwrap();
+
+        // the second put and the get find key 3 in the shelter
+        oram.put(3, vec![1]).unwrap();
+        oram.put(3, vec![2]).unwrap();
+        assert_eq!(oram.get(3).unwrap(), Some(vec![2]));
+    }
+
+    #[test]
+    fn batch_of_sheltered_and_fetched_keys() {
+        let n: u64 = 16;
+        let mut oram = OramBuilder::new(n, 8).shelter_size(8).build().unwrap();
+
+        // keys 1 and 2 are in the shelter for the second batch, key 3 is not
+        oram.put_many(vec![(1, vec![1]), (2, vec![2])]).unwrap();
+        oram.put_many(vec![(3, vec![30]), (2, vec![20]), (1, vec![10])])
+            .unwrap();
+        assert_eq!(oram.len(), 3);
+        assert_eq!(
+            oram.get_many(&[1, 2, 3, 4]).unwrap(),
+            vec![Some(vec![10]), Some(vec![20]), Some(vec![30]), None]
+        );
+    }
+
+    #[test]
+    fn get_unwritten_and_removed_keys() {
+        init_logger();
+
+        let n: u64 = 8;
+        let mut oram = SqrtOram::new(n, TEST_BLOCK_SIZE).unwrap();
+        for i in 0..n {
+            assert_eq!(oram.get(i).unwrap(), None);
+        }
+        assert!(oram.is_empty());
+
+        for i in 0..n {
+            oram.put(i, vec![i as u8]).unwrap();
+        }
+        assert_eq!(oram.len(), n);
+        // overwriting does not change the count
+        oram.put(3, vec![0]).unwrap();
+        assert_eq!(oram.len(), n);
+
+        assert_eq!(oram.remove(3).unwrap(), Some(vec![0]));
+        assert_eq!(oram.remove(3).unwrap(), None);
+        assert_eq!(oram.get(3).unwrap(), None);
+        assert_eq!(oram.len(), n - 1);
+        for i in (0..n).filter(|&i| i != 3) {
+            assert_eq!(oram.get(i).unwrap(), Some(vec![i as u8]));
+        }
+    }
+
+    #[test]
+    fn keep_exact_length() {
+        let mut oram = SqrtOram::new(4, TEST_BLOCK_SIZE).unwrap();
+        // trailing bytes equal to the padding are kept
+        oram.put(0, vec![1, 255, 255]).unwrap();
+        oram.put(1, vec![]).unwrap();
+        assert_eq!(oram.get(0).unwrap(), Some(vec![1, 255, 255]));
+        assert_eq!(oram.get(1).unwrap(), Some(vec![]));
+    }
+
+    #[test]
+    fn update_in_one_access() {
+        let n: u64 = 4;
+        let mut oram = SqrtOram::new(n, 8).unwrap();
+
+        let increment = |data: Option<&mut Data>| {
+            if let Some(data) = data {
+                data[0] += 1
+            }
+        };
+        oram.put(1, vec![0]).unwrap();
+        for i in 0..10 {
+            let count = oram.count;
+            oram.update(1, increment).unwrap();
+            assert_eq!(oram.count, (count + 1) % oram.shelter_size);
+            assert_eq!(oram.get(1).unwrap(), Some(vec![i + 1]));
+        }
+        assert!(oram.update(2, |data| data.is_none()).unwrap());
+        assert_eq!(oram.get(2).unwrap(), None);
+
+        let append = |data: Option<&mut Data>| data.unwrap().extend_from_slice(&[0; 8]);
+        assert!(matches!(
+            oram.update(1, append),
+            Err(OramError::Capacity { len: 9, .. })
+        ));
+        assert_eq!(oram.get(1).unwrap(), Some(vec![10]));
+    }
+
+    #[test]
+    fn batch_get_and_put() {
+        let n: u64 = 16;
+        let mut oram = SqrtOram::new(n, 8).unwrap();
+
+        // larger than the shelter, with a duplicate key
+        let mut items: Vec<(u64, Data)> = (0..n - 2).map(|i| (i, vec![i as u8])).collect();
+        items.push((3, vec![100]));
+        oram.put_many(items).unwrap();
+        oram.put(5, vec![50]).unwrap();
+
+        let keys = [5, 3, 5, 0, n - 1];
+        let expected = vec![
+            Some(vec![50]),
+            Some(vec![100]),
+            Some(vec![50]),
+            Some(vec![0]),
+            None,
+        ];
+        assert_eq!(oram.get_many(&keys).unwrap(), expected);
+        assert_eq!(oram.get_many(&[]).unwrap(), vec![]);
+        for i in (0..n - 2).filter(|&i| i != 3 && i != 5) {
+            assert_eq!(oram.get(i).unwrap(), Some(vec![i as u8]));
+        }
+    }
+
+    #[test]
+    fn batch_fetches_padded_number_of_blocks() {
+        // with the shelter as large as the ORAM, `count` tracks fetches modulo n
+        let n: u64 = 16;
+        let mut oram = OramBuilder::new(n, 8)
+            .shelter_size(n)
+            .batch_padding(4)
+            .build()
+            .unwrap();
+
+        for (keys, fetches) in &[(vec![1], 4), (vec![1, 1, 1], 4), (vec![2, 3, 4, 5, 6], 8)] {
+            let count = oram.count;
+            oram.get_many(keys).unwrap();
+            assert_eq!((oram.count + n - count) % n, *fetches);
+        }
+        assert!(matches!(
+            oram.get_many(&[1, n]),
+            Err(OramError::KeyOutOfRange { .. })
+        ));
+    }
+
+    #[test]
+    fn shuffle_with_bitonic_network() {
+        let n: u64 = 21;
+        let mut oram = OramBuilder::new(n, 8)
+            .sorting_network(Network::Bitonic)
+            .shuffle_mode(ShuffleMode::SortByTag)
+            .build()
+            .unwrap();
+
+        // enough accesses for several reshuffles
+        for round in 0..3u8 {
+            for i in 0..n {
+                oram.put(i, vec![i as u8, round]).unwrap();
+            }
+        }
+        for i in 0..n {
+            assert_eq!(oram.get(i).unwrap(), Some(vec![i as u8, 2]));
+        }
+    }
+
+    #[test]
+    fn sort_in_chunks() {
+        let n: u64 = 40;
+        for &shuffle in &[ShuffleMode::SortByTag, ShuffleMode::Melbourne] {
+            let mut oram = OramBuilder::new(n, 8)
+                .sort_memory(10)
+                .shuffle_mode(shuffle)
+                .build()
+                .unwrap();
+
+            for round in 0..3u8 {
+                for i in 0..n {
+                    oram.put(i, vec![i as u8, round]).unwrap();
+                }
+            }
+            for i in 0..n {
+                assert_eq!(oram.get(i).unwrap(), Some(vec![i as u8, 2]));
+            }
+        }
+    }
+
+    #[test]
+    fn oblivious_position_lookup() {
+        let n: u64 = 30;
+        let mut oram = OramBuilder::new(n, 8)
+            .position_lookup(PositionLookup::Oblivious)
+            .build()
+            .unwrap();
+        // the table built with a sorting network is the one written directly
+        let sorted = oram.positions.clone();
+        oram.tuning.lookup = PositionLookup::Direct;
+        oram.map_positions();
+        assert_eq!(oram.positions, sorted);
+        oram.tuning.lookup = PositionLookup::Oblivious;
+
+        for round in 0..3u8 {
+            for i in 0..n {
+                oram.put(i, vec![i as u8, round]).unwrap();
+            }
+        }
+        for i in 0..n {
+            assert_eq!(oram.get(i).unwrap(), Some(vec![i as u8, 2]));
+        }
+
+        let oblivious = (0..oram.dummy_range().end)
+            .map(|k| oram.locate(k))
+            .collect::<Vec<_>>();
+        oram.tuning.lookup = PositionLookup::Direct;
+        let direct = (0..oram.dummy_range().end)
+            .map(|k| oram.locate(k))
+            .collect::<Vec<_>>();
+        assert_eq!(oblivious, direct);
+    }
+
+    #[test]
+    fn grow_and_shrink() {
+        init_logger();
+
+        let mut oram = SqrtOram::new(8, TEST_BLOCK_SIZE).unwrap();
+        for i in 0..8 {
+            oram.put(i, vec![i as u8]).unwrap();
+        }
+
+        oram.resize(20).unwrap();
+        assert_eq!((oram.num_blocks(), oram.shelter_size), (20, 4));
+        assert_eq!(oram.len(), 8);
+        for i in 8..20 {
+            assert_eq!(oram.get(i).unwrap(), None);
+            oram.put(i, vec![i as u8]).unwrap();
+        }
+        for i in 0..20 {
+            assert_eq!(oram.get(i).unwrap(), Some(vec![i as u8]));
+        }
 
-        oram.put(0 as u32, vec![0; TEST_BLOCK_SIZE + 1]);
+        assert!(matches!(oram.resize(10), Err(OramError::InvalidConfig(_))));
+        assert_eq!(oram.num_blocks(), 20);
+        for i in 10..20 {
+            oram.remove(i).unwrap();
+        }
+        oram.resize(10).unwrap();
+        assert_eq!((oram.num_blocks(), oram.shelter_size), (10, 3));
+        for i in oram.capacity..20 + 2 * 4 {
+            assert_eq!(oram.db.get(&i.to_be_bytes()).unwrap(), None);
+        }
+        for i in 0..10 {
+            assert_eq!(oram.get(i).unwrap(), Some(vec![i as u8]));
+        }
+        assert!(matches!(oram.get(10), Err(OramError::KeyOutOfRange { .. })));
+    }
+
+    #[test]
+    fn access_out_of_range() {
+        init_logger();
+
+        let n: u64 = 8;
+        let mut oram = SqrtOram::new(n, TEST_BLOCK_SIZE).unwrap();
+
+        assert_eq!(oram.get(n), Err(OramError::KeyOutOfRange { key: n, n }));
+        assert_eq!(
+            oram.put(u64::MAX, vec![]),
+            Err(OramError::KeyOutOfRange { key: u64::MAX, n })
+        );
+    }
+
+    #[test]
+    fn detect_corrupt_block() {
+        init_logger();
+
+        let n: u64 = 8;
+        let mut oram = SqrtOram::new(n, TEST_BLOCK_SIZE).unwrap();
+
+        // every access scans the shelter
+        let position = oram.shelter_range().start;
+        oram.db.put(&position.to_be_bytes(), b"garbage").unwrap();
+        assert_eq!(oram.get(0), Err(OramError::Corruption(position)));
+    }
+
+    #[test]
+    fn detect_replaced_block() {
+        init_logger();
+
+        let n: u64 = 8;
+        let mut oram = SqrtOram::new(n, TEST_BLOCK_SIZE).unwrap();
+
+        let position = oram.shelter_range().start;
+        let other = oram.db.get(&0u64.to_be_bytes()).unwrap().unwrap();
+        oram.db.put(&position.to_be_bytes(), &other).unwrap();
+        assert_eq!(oram.get(0), Err(OramError::Integrity(Some(position))));
     }
 
     #[test]
     fn put_right_sized_data() {
         init_logger();
 
-        let n = 8 as usize;
-        let mut oram = SqrtOram::new(n, TEST_BLOCK_SIZE);
+        let n: u64 = 8;
+        let mut oram = SqrtOram::new(n, TEST_BLOCK_SIZE).unwrap();
 
-        oram.put(0 as u32, vec![0; TEST_BLOCK_SIZE]);
-        oram.put(0 as u32, vec![0; TEST_BLOCK_SIZE - 1]);
+        oram.put(0, vec![0; TEST_BLOCK_SIZE]).unwrap();
+        oram.put(0, vec![0; TEST_BLOCK_SIZE - 1]).unwrap();
     }
 
     #[test]
@@ -543,15 +1374,15 @@ mod tests {
         init_logger();
         info!("logger initialized");
 
-        let n = 2048 as usize;
-        let mut oram = SqrtOram::new(n, TEST_BLOCK_SIZE);
+        let n: u64 = 2048;
+        let mut oram = SqrtOram::new(n, TEST_BLOCK_SIZE).unwrap();
         info!("oram initialized");
 
         for i in 0..n {
             let mut data = [0u8; TEST_BLOCK_SIZE];
             data[0..8].copy_from_slice(&i.to_le_bytes());
             assert_eq!(oram.count, i % oram.shelter_size);
-            oram.put(i as u32, data.to_vec());
+            oram.put(i, data.to_vec()).unwrap();
         }
         info!("oram put done");
 
@@ -559,7 +1390,7 @@ mod tests {
             let mut data = [0u8; TEST_BLOCK_SIZE];
             data[0..8].copy_from_slice(&i.to_le_bytes());
             assert_eq!(oram.count % oram.shelter_size, (n + i) % oram.shelter_size);
-            assert_eq!(&data[..], &oram.get(i as u32).unwrap()[..]);
+            assert_eq!(&data[..], &oram.get(i).unwrap().unwrap()[..]);
         }
         info!("oram get done");
     }
@@ -576,16 +1407,16 @@ mod tests {
         init_logger();
         info!("logger initialized");
 
-        let n = 8192 as usize;
-        let mut oram = SqrtOram::new(n, TEST_BLOCK_SIZE);
+        let n: u64 = 8192;
+        let mut oram = SqrtOram::new(n, TEST_BLOCK_SIZE).unwrap();
         info!("oram initialized");
 
         for i in 0..n {
             info!("putting for {} time", i);
-            let k = 0 as usize;
+            let k: u64 = 0;
             let mut data = [0u8; TEST_BLOCK_SIZE];
             data[0..8].copy_from_slice(&k.to_le_bytes());
-            oram.put(k as u32, vec![0; TEST_BLOCK_SIZE]);
+            oram.put(k, vec![0; TEST_BLOCK_SIZE]).unwrap();
         }
         info!("oram put done");
     }
@@ -597,7 +1428,7 @@ mod tests {
         fs::remove_dir_all(path)
             .or_else(|e| -> Result<()> {
                 if e.kind() == ErrorKind::NotFound {
-                    return Ok(());
+                    Ok(())
                 } else {
                     Err(e)
                 }
@@ -605,6 +1436,32 @@ mod tests {
             .expect("remove existing db directory");
     }
 
+    #[test]
+    #[ignore]
+    // cargo test --release --package oram --lib -- tests::leveldb_resize --exact --nocapture --ignored
+    fn leveldb_resize() {
+        init_logger();
+
+        let db_name = "db_resize";
+        remove_db_folder(db_name);
+
+        let mut oram = SqrtOram::open(db_name, 16, TEST_BLOCK_SIZE).unwrap();
+        for i in 0..16 {
+            oram.put(i, vec![i as u8]).unwrap();
+        }
+        oram.resize(64).unwrap();
+        oram.close().unwrap();
+
+        let mut oram = SqrtOram::open(db_name, 64, TEST_BLOCK_SIZE).unwrap();
+        for i in 0..64 {
+            let expected = if i < 16 { Some(vec![i as u8]) } else { None };
+            assert_eq!(oram.get(i).unwrap(), expected);
+        }
+        oram.close().unwrap();
+
+        remove_db_folder(db_name);
+    }
+
     #[test]
     #[ignore]
     // Ignore this test as it takes long time to complete.
@@ -621,17 +1478,17 @@ mod tests {
 
         remove_db_folder(db_name);
 
-        let n = 512 as usize;
-        let mut oram = SqrtOram::open(db_name, n, TEST_BLOCK_SIZE);
+        let n: u64 = 512;
+        let mut oram = SqrtOram::open(db_name, n, TEST_BLOCK_SIZE).unwrap();
 
         for i in 0..n {
             assert_eq!(oram.count, i % oram.shelter_size);
-            oram.put(i as u32, i.to_be_bytes().to_vec());
+            oram.put(i, i.to_be_bytes().to_vec()).unwrap();
         }
 
         for i in 0..n {
             assert_eq!(oram.count % oram.shelter_size, (n + i) % oram.shelter_size);
-            assert_eq!(i.to_be_bytes().to_vec(), oram.get(i as u32).unwrap());
+            assert_eq!(i.to_be_bytes().to_vec(), oram.get(i).unwrap().unwrap());
         }
     }
 
@@ -648,11 +1505,12 @@ mod tests {
 
         let db_name = "db";
 
-        let n = 512 as usize;
-        let mut oram = SqrtOram::open(db_name, n, TEST_BLOCK_SIZE);
+        let n: u64 = 512;
+        let mut oram = SqrtOram::open(db_name, n, TEST_BLOCK_SIZE).unwrap();
+        assert_eq!(oram.len(), n);
 
         for i in 0..n {
-            assert_eq!(i.to_be_bytes().to_vec(), oram.get(i as u32).unwrap());
+            assert_eq!(i.to_be_bytes().to_vec(), oram.get(i).unwrap().unwrap());
         }
 
         // clean up
@@ -0,0 +1,438 @@
+// Copyright 2020 ADVANCA PTE. LTD.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An oblivious hash map over `SqrtOram`
+//!
+//! Keys are hashed into two candidate buckets (two-choice hashing). Each bucket is
+//! one ORAM block holding up to `bucket_size` entries. Every operation reads both
+//! candidate buckets and writes both back, so the host always sees four ORAM
+//! accesses, whether the key exists or not and whatever the operation is.
+//!
+//! Buckets are chosen by a hash keyed with a secret derived from the sealing key
+//! of the store and never stored, so the host can neither predict the buckets
+//! of a key nor pick keys that overflow one bucket. A map therefore needs a
+//! sealed store.
+
+#[cfg(feature = "sgx")]
+use sgx_tstd::{self as std, prelude::v1::*};
+
+use crate::builder::default_rng;
+use crate::convert::TryInto;
+use crate::{deserialize, serialize, serialized_size};
+use crate::{Deserialize, Input, VarBlake2b, VariableOutput};
+use crate::{DeserializeOwned, Key, OramBuilder, OramError, Result, Serialize, SqrtOram};
+use std::marker::PhantomData;
+
+/// Default number of entries per bucket
+const BUCKET_SIZE: usize = 4;
+
+/// Label deriving the bucket hash key from the sealing key
+const HASH_KEY_LABEL: &[u8] = b"oram map";
+
+#[cfg_attr(feature = "sgx", serde(crate = "serde_sgx"))]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Entry {
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+type Bucket = Vec<Entry>;
+
+enum Op {
+    Get,
+    Insert(Vec<u8>),
+    Remove,
+}
+
+/// A map from byte-string keys to serde values, stored obliviously in ORAM
+///
+/// # Examples
+///
+/// ```
+/// use oram::ObliviousMap;
+///
+/// let mut map: ObliviousMap<str, u64> = ObliviousMap::new(16, 8, 8).unwrap();
+/// assert_eq!(map.insert("alice", &1).unwrap(), None);
+/// assert_eq!(map.get("alice").unwrap(), Some(1));
+/// assert_eq!(map.remove("alice").unwrap(), Some(1));
+/// assert_eq!(map.get("alice").unwrap(), None);
+/// ```
+pub struct ObliviousMap<K: ?Sized, V> {
+    oram: SqrtOram,
+    /// Maximum number of entries in a bucket
+    bucket_size: usize,
+    /// Maximum length of a key in bytes
+    max_key_len: usize,
+    /// Maximum encoded size of a value in bytes
+    max_value_size: usize,
+    /// Secret key of the bucket hash, derived from the sealing key
+    hash_key: Key,
+    phantom: PhantomData<fn(&K) -> V>,
+}
+
+impl<K, V> ObliviousMap<K, V>
+where
+    K: AsRef<[u8]> + ?Sized,
+    V: Serialize + DeserializeOwned,
+{
+    /// Create an in-memory map for about `capacity` entries, sealed under a
+    /// random key
+    ///
+    /// - `max_key_len`: maximum length of a key in bytes
+    /// - `max_value_size`: maximum encoded size of a value in bytes
+    ///
+    /// Buckets are kept half full on average, which makes overflowing both
+    /// candidate buckets of a key unlikely.
+    pub fn new(capacity: u64, max_key_len: usize, max_value_size: usize) -> Result<Self> {
+        let entries_per_bucket = BUCKET_SIZE as u64 / 2;
+        let num_buckets =
+            (capacity.saturating_add(entries_per_bucket - 1) / entries_per_bucket).max(1);
+        let block_size = Self::required_block_size(BUCKET_SIZE, max_key_len, max_value_size);
+        let mut key = Key::default();
+        default_rng(&mut key);
+        let oram = OramBuilder::new(num_buckets, block_size)
+            .encryption_key(key)
+            .build()?;
+        Self::create(oram, BUCKET_SIZE, max_key_len, max_value_size)
+    }
+
+    /// Create an empty map in `oram`, e.g. one made by `OramBuilder`
+    ///
+    /// Every block of `oram` is cleared. The block size of `oram` has to be at
+    /// least `required_block_size(bucket_size, max_key_len, max_value_size)`.
+    ///
+    /// # Errors
+    ///
+    /// `OramError::InvalidConfig` if `oram` has no encryption or integrity key
+    pub fn create(
+        oram: SqrtOram,
+        bucket_size: usize,
+        max_key_len: usize,
+        max_value_size: usize,
+    ) -> Result<Self> {
+        let mut map = Self::with_hash_key(oram, bucket_size, max_key_len, max_value_size)?;
+        let empty = serialize(&Bucket::new()).expect("serialize bucket");
+        for i in 0..map.oram.num_blocks() {
+            map.oram.put(i, empty.clone())?;
+        }
+        Ok(map)
+    }
+
+    /// Use the map previously created in `oram` with the same parameters
+    ///
+    /// # Errors
+    ///
+    /// `OramError::InvalidConfig` if `oram` has no encryption or integrity key
+    pub fn open(
+        oram: SqrtOram,
+        bucket_size: usize,
+        max_key_len: usize,
+        max_value_size: usize,
+    ) -> Result<Self> {
+        Self::with_hash_key(oram, bucket_size, max_key_len, max_value_size)
+    }
+
+    fn with_hash_key(
+        oram: SqrtOram,
+        bucket_size: usize,
+        max_key_len: usize,
+        max_value_size: usize,
+    ) -> Result<Self> {
+        let hash_key = oram.db.derive_key(HASH_KEY_LABEL).ok_or_else(|| {
+            OramError::InvalidConfig("a map needs a sealed store to hide its buckets".into())
+        })?;
+        if bucket_size == 0 {
+            return Err(OramError::InvalidConfig(
+                "bucket size must be positive".into(),
+            ));
+        }
+        if oram.block_size() < Self::required_block_size(bucket_size, max_key_len, max_value_size) {
+            return Err(OramError::InvalidConfig(
+                "block size is too small for a full bucket".into(),
+            ));
+        }
+        Ok(ObliviousMap {
+            oram,
+            bucket_size,
+            max_key_len,
+            max_value_size,
+            hash_key,
+            phantom: PhantomData,
+        })
+    }
+
+    /// The block size needed to store a full bucket
+    pub fn required_block_size(
+        bucket_size: usize,
+        max_key_len: usize,
+        max_value_size: usize,
+    ) -> usize {
+        // bincode prefixes every `Vec` with a u64 length
+        8 + bucket_size * (8 + max_key_len + 8 + max_value_size)
+    }
+
+    /// Read the value of `key`
+    pub fn get(&mut self, key: &K) -> Result<Option<V>> {
+        let key = self.check_key(key)?;
+        self.access(key, Op::Get)
+    }
+
+    /// Insert or replace the value of `key`, returning the previous value
+    ///
+    /// # Errors
+    ///
+    /// `OramError::Full` if both candidate buckets of `key` are full. The
+    /// buckets are still accessed, so a failed insert looks like any other
+    /// operation to the host.
+    pub fn insert(&mut self, key: &K, value: &V) -> Result<Option<V>> {
+        let key = self.check_key(key)?;
+        let size = serialized_size(value).map_err(|e| OramError::Serialization(e.to_string()))?;
+        if size as usize > self.max_value_size {
+            return Err(OramError::Capacity {
+                len: size as usize,
+                block_size: self.max_value_size,
+            });
+        }
+        let value = serialize(value).map_err(|e| OramError::Serialization(e.to_string()))?;
+        self.access(key, Op::Insert(value))
+    }
+
+    /// Remove `key`, returning its value
+    pub fn remove(&mut self, key: &K) -> Result<Option<V>> {
+        let key = self.check_key(key)?;
+        self.access(key, Op::Remove)
+    }
+
+    /// Unwrap the underlying ORAM
+    pub fn into_inner(self) -> SqrtOram {
+        self.oram
+    }
+
+    fn check_key<'a>(&self, key: &'a K) -> Result<&'a [u8]> {
+        let key = key.as_ref();
+        if key.len() > self.max_key_len {
+            return Err(OramError::Capacity {
+                len: key.len(),
+                block_size: self.max_key_len,
+            });
+        }
+        Ok(key)
+    }
+
+    /// The two candidate buckets of `key`
+    fn buckets(&self, key: &[u8]) -> (u64, u64) {
+        let mut hasher = VarBlake2b::new_keyed(&self.hash_key, 16);
+        hasher.input(key);
+        let hash = hasher.vec_result();
+        let num_buckets = self.oram.num_blocks();
+        let first = u64::from_be_bytes(hash[0..8].try_into().expect("slice to array"));
+        let second = u64::from_be_bytes(hash[8..16].try_into().expect("slice to array"));
+        (first % num_buckets, second % num_buckets)
+    }
+
+    /// Run `op` on `key`, always with two bucket reads followed by two bucket writes
+    fn access(&mut self, key: &[u8], op: Op) -> Result<Option<V>> {
+        let (first, second) = self.buckets(key);
+        let mut buckets = [self.read_bucket(first)?, self.read_bucket(second)?];
+        if first == second {
+            // both candidates are the same block, only one copy is meaningful
+            buckets[1].clear();
+        }
+
+        let found = buckets.iter().enumerate().find_map(|(b, bucket)| {
+            bucket
+                .iter()
+                .position(|entry| entry.key[..] == key[..])
+                .map(|i| (b, i))
+        });
+        let mut result = Ok(None);
+        match (op, found) {
+            (Op::Get, Some((b, i))) => result = Ok(Some(buckets[b][i].value.clone())),
+            (Op::Insert(value), Some((b, i))) => {
+                let old = std::mem::replace(&mut buckets[b][i].value, value);
+                result = Ok(Some(old));
+            }
+            (Op::Insert(value), None) => {
+                let b = if first == second || buckets[0].len() <= buckets[1].len() {
+                    0
+                } else {
+                    1
+                };
+                if buckets[b].len() < self.bucket_size {
+                    buckets[b].push(Entry {
+                        key: key.to_vec(),
+                        value,
+                    });
+                } else {
+                    result = Err(OramError::Full);
+                }
+            }
+            (Op::Remove, Some((b, i))) => result = Ok(Some(buckets[b].remove(i).value)),
+            (Op::Get, None) | (Op::Remove, None) => {}
+        }
+
+        let [first_bucket, second_bucket] = buckets;
+        let second_bucket = if first == second {
+            &first_bucket
+        } else {
+            &second_bucket
+        };
+        self.write_bucket(first, &first_bucket)?;
+        self.write_bucket(second, second_bucket)?;
+
+        match result? {
+            Some(value) => deserialize(&value)
+                .map(Some)
+                .map_err(|e| OramError::Serialization(e.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    fn read_bucket(&mut self, i: u64) -> Result<Bucket> {
+        match self.oram.get(i)? {
+            Some(data) => deserialize(&data).map_err(|e| OramError::Serialization(e.to_string())),
+            None => Ok(Bucket::new()),
+        }
+    }
+
+    fn write_bucket(&mut self, i: u64, bucket: &[Entry]) -> Result<()> {
+        self.oram
+            .put(i, serialize(bucket).expect("serialize bucket"))
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::Backend;
+
+    const KEY: Key = [5; 32];
+
+    #[test]
+    fn insert_get_remove() {
+        let n = 32;
+        let mut map: ObliviousMap<[u8], u64> = ObliviousMap::new(n, 16, 8).unwrap();
+
+        for i in 0..n {
+            let key = format!("key {}", i);
+            assert_eq!(map.insert(key.as_bytes(), &i).unwrap(), None);
+        }
+        for i in 0..n {
+            let key = format!("key {}", i);
+            assert_eq!(map.get(key.as_bytes()).unwrap(), Some(i));
+        }
+        assert_eq!(map.insert(b"key 0", &100).unwrap(), Some(0));
+        assert_eq!(map.get(b"key 0").unwrap(), Some(100));
+        assert_eq!(map.remove(b"key 0").unwrap(), Some(100));
+        assert_eq!(map.get(b"key 0").unwrap(), None);
+        assert_eq!(map.remove(b"key 0").unwrap(), None);
+        assert_eq!(map.get(b"missing").unwrap(), None);
+    }
+
+    #[test]
+    fn reject_long_key_and_large_value() {
+        let mut map: ObliviousMap<str, String> = ObliviousMap::new(4, 4, 16).unwrap();
+
+        assert!(matches!(
+            map.insert("too long", &String::new()),
+            Err(OramError::Capacity { .. })
+        ));
+        assert!(matches!(
+            map.insert("key", &"x".repeat(16)),
+            Err(OramError::Capacity { .. })
+        ));
+    }
+
+    #[test]
+    fn report_full_buckets() {
+        let block_size = ObliviousMap::<str, u8>::required_block_size(2, 8, 1);
+        let oram = OramBuilder::new(1, block_size)
+            .encryption_key(KEY)
+            .build()
+            .unwrap();
+        let mut map: ObliviousMap<str, u8> = ObliviousMap::create(oram, 2, 8, 1).unwrap();
+
+        map.insert("a", &1).unwrap();
+        map.insert("b", &2).unwrap();
+        assert_eq!(map.insert("c", &3), Err(OramError::Full));
+        assert_eq!(map.get("a").unwrap(), Some(1));
+        assert_eq!(map.get("b").unwrap(), Some(2));
+        assert_eq!(map.get("c").unwrap(), None);
+    }
+
+    #[test]
+    fn reopen_with_derived_hash_key() {
+        let path = std::env::temp_dir().join("oram_map");
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_dir_all(path);
+        let block_size = ObliviousMap::<str, u64>::required_block_size(BUCKET_SIZE, 8, 8);
+        let builder = OramBuilder::new(8, block_size)
+            .backend(Backend::LevelDb)
+            .path(path)
+            .encryption_key(KEY);
+
+        let mut map: ObliviousMap<str, u64> =
+            ObliviousMap::create(builder.clone().build().unwrap(), BUCKET_SIZE, 8, 8).unwrap();
+        for (i, key) in ["a", "b", "c", "d"].iter().enumerate() {
+            map.insert(key, &(i as u64)).unwrap();
+        }
+        let mut oram = map.into_inner();
+        // the hash key is not stored
+        assert_eq!(oram.db.get(b"map").unwrap(), None);
+        drop(oram);
+
+        let oram = builder.build().unwrap();
+        let mut map: ObliviousMap<str, u64> = ObliviousMap::open(oram, BUCKET_SIZE, 8, 8).unwrap();
+        assert_eq!(map.get("c").unwrap(), Some(2));
+        drop(map);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn reject_unsealed_store() {
+        let block_size = ObliviousMap::<str, u64>::required_block_size(BUCKET_SIZE, 8, 8);
+        let oram = SqrtOram::new(8, block_size).unwrap();
+        assert!(matches!(
+            ObliviousMap::<str, u64>::create(oram, BUCKET_SIZE, 8, 8),
+            Err(OramError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn constant_number_of_accesses() {
+        // with the shelter as large as the ORAM, `count` tracks accesses modulo 8
+        let n = 8;
+        let block_size = ObliviousMap::<str, u8>::required_block_size(BUCKET_SIZE, 8, 1);
+        let oram = OramBuilder::new(n, block_size)
+            .encryption_key(KEY)
+            .shelter_size(n)
+            .build()
+            .unwrap();
+        let mut map: ObliviousMap<str, u8> = ObliviousMap::create(oram, BUCKET_SIZE, 8, 1).unwrap();
+        map.insert("present", &1).unwrap();
+
+        for op in 0..6 {
+            let before = map.oram.count;
+            let _ = match op {
+                0 => map.get("present"),
+                1 => map.get("absent"),
+                2 => map.insert("present", &2),
+                3 => map.insert("absent", &2),
+                4 => map.remove("present"),
+                _ => map.remove("nothing"),
+            };
+            assert_eq!((map.oram.count + n - before) % n, 4);
+        }
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright 2020 ADVANCA PTE. LTD.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sorting blocks on the rayon thread pool, for backends with concurrent I/O
+//!
+//! Every backend qualifies, sealed and traced or not. LevelDB requests take
+//! turns on one handle, so there the threads mostly share the sealing.
+
+use crate::db::SharedStorage;
+use crate::journal::{slot_key, Journal, JOURNAL_KEY};
+use crate::sort::{self, Network};
+use crate::{serialize, Block, BlockCache, Box, OramError, Result};
+use std::ops::Range;
+use std::sync::Mutex;
+
+/// What `SqrtOram::sort_blocks()` needs to sort from several threads
+pub(crate) struct BlockSort<'a> {
+    pub storage: &'a dyn SharedStorage,
+    pub cache: &'a mut [BlockCache],
+    pub block_size: usize,
+}
+
+impl BlockSort<'_> {
+    /// Sort the blocks in `range`, journaling every swap in its slot
+    pub fn sort<C>(self, network: Network, range: Range<u64>, cmp: C) -> Result<()>
+    where
+        C: Fn(&Block, &Block) -> bool + Sync,
+    {
+        let storage = self.storage;
+        let block_size = self.block_size;
+        let cache = Mutex::new(self.cache);
+        let put = |key: &[u8], journal: &Journal| {
+            storage.put(key, &serialize(journal).expect("serialize journal"))
+        };
+        let put_block = |k: u64, block: &Block| {
+            storage.put(
+                &k.to_be_bytes(),
+                &serialize(block).expect("serialize block"),
+            )
+        };
+
+        // from now on the blocks are in no particular order
+        put(JOURNAL_KEY, &Journal::Shuffled)?;
+        sort::try_par_sort_with(
+            network,
+            range,
+            cmp,
+            |k| {
+                let block = Block::decode(k, storage.get(&k.to_be_bytes())?, block_size)?;
+                if lock(&cache)[k as usize] != block.header {
+                    return Err(OramError::Integrity(Some(k)));
+                }
+                Ok(block)
+            },
+            |i, a, j, b| {
+                let slot = slot_key(i.min(j));
+                let pair = Journal::Pair(i, a.clone(), j, b.clone(), Box::new(Journal::Shuffled));
+                put(&slot, &pair)?;
+                put_block(i, a)?;
+                put_block(j, b)?;
+                put(&slot, &Journal::Shuffled)?;
+                let mut cache = lock(&cache);
+                cache[i as usize] = a.header.clone();
+                cache[j as usize] = b.header.clone();
+                Ok(())
+            },
+        )
+    }
+}
+
+fn lock<'a, 'b>(
+    cache: &'a Mutex<&'b mut [BlockCache]>,
+) -> std::sync::MutexGuard<'a, &'b mut [BlockCache]> {
+    cache.lock().expect("block cache lock")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::journal::slot_key;
+    use crate::sort::Network;
+    use crate::trace::Recorder;
+    use crate::{Backend, OramBuilder, ShuffleMode};
+
+    #[test]
+    fn shuffle_in_parallel() {
+        let n: u64 = 50;
+        for &network in &[Network::OddEvenMerge, Network::Bitonic] {
+            let mut oram = OramBuilder::new(n, 8)
+                .sorting_network(network)
+                .shuffle_mode(ShuffleMode::SortByTag)
+                .build()
+                .unwrap();
+            assert!(oram.db.shared().is_some());
+
+            for round in 0..3u8 {
+                for i in 0..n {
+                    oram.put(i, vec![i as u8, round]).unwrap();
+                }
+            }
+            for i in 0..n {
+                assert_eq!(oram.get(i).unwrap(), Some(vec![i as u8, 2]));
+            }
+        }
+    }
+
+    #[test]
+    fn shuffle_sealed_and_traced_in_parallel() {
+        let n: u64 = 50;
+        let recorder = Recorder::in_memory();
+        let mut oram = OramBuilder::new(n, 8)
+            .encryption_key([3; 32])
+            .trace(&recorder)
+            .shuffle_mode(ShuffleMode::SortByTag)
+            .build()
+            .unwrap();
+        assert!(oram.db.shared().is_some());
+
+        for round in 0..3u8 {
+            for i in 0..n {
+                oram.put(i, vec![i as u8, round]).unwrap();
+            }
+        }
+        for i in 0..n {
+            assert_eq!(oram.get(i).unwrap(), Some(vec![i as u8, 2]));
+        }
+        assert!(recorder.trace().unwrap().summary().puts > 0);
+    }
+
+    #[test]
+    fn shuffle_leveldb_in_parallel() {
+        let n: u64 = 20;
+        let path = std::env::temp_dir().join("oram_parallel_leveldb");
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_dir_all(path);
+        let mut oram = OramBuilder::new(n, 8)
+            .backend(Backend::LevelDb)
+            .path(path)
+            .encryption_key([3; 32])
+            .shuffle_mode(ShuffleMode::SortByTag)
+            .build()
+            .unwrap();
+        assert!(oram.db.shared().is_some());
+
+        for round in 0..3u8 {
+            for i in 0..n {
+                oram.put(i, vec![i as u8, round]).unwrap();
+            }
+        }
+        for i in 0..n {
+            assert_eq!(oram.get(i).unwrap(), Some(vec![i as u8, 2]));
+        }
+        // only parallel sorts journal their swaps in slots
+        assert!(oram.db.get(&slot_key(0)).unwrap().is_some());
+        drop(oram);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+// Copyright 2020 ADVANCA PTE. LTD.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Oblivious full scan of an ORAM
+//!
+//! A scan ends the current epoch early. The blocks are rearranged with the same
+//! oblivious sort as a reshuffle, which leaves block `k` at position `k`, so the
+//! real area can be read once in order. The next access, or the end of `scan()`,
+//! rotates the salt and shuffles the blocks again, starting a fresh epoch.
+
+use crate::{Data, OramError, Result, SqrtOram};
+
+/// Iterator over the contents of all keys in index order, see `SqrtOram::iter()`
+pub struct Scan<'a> {
+    oram: &'a mut SqrtOram,
+    next: u64,
+}
+
+impl SqrtOram {
+    /// Iterate over the data of every key, in key order
+    ///
+    /// Reads the whole storage once after an oblivious sort, instead of `n`
+    /// separate accesses. The iterator yields `None` for empty keys.
+    ///
+    /// The blocks are shuffled again by the next access. Use `scan()` to do
+    /// that right away.
+    pub fn iter(&mut self) -> Result<Scan<'_>> {
+        if !self.scanning {
+            self.rearrange()?;
+            self.scanning = true;
+        }
+        Ok(Scan {
+            oram: self,
+            next: 0,
+        })
+    }
+
+    /// Visit the data of every key in key order, see `iter()`
+    ///
+    /// Stops at the first error.
+    pub fn scan<F>(&mut self, mut f: F) -> Result<()>
+    where
+        F: FnMut(u64, Option<Data>),
+    {
+        for item in self.iter()? {
+            let (k, data) = item?;
+            f(k, data);
+        }
+        self.finish_scan()
+    }
+
+    /// Start a new epoch if blocks are still arranged for a scan
+    pub(crate) fn finish_scan(&mut self) -> Result<()> {
+        if self.scanning {
+            self.permute()?;
+            self.scanning = false;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for Scan<'a> {
+    type Item = Result<(u64, Option<Data>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next == self.oram.num_blocks() {
+            return None;
+        }
+        let k = self.next;
+        self.next += 1;
+        Some(self.oram.read_block(k).and_then(|block| {
+            if block.header.dummy || block.header.index != k {
+                // rearranged blocks are in key order
+                Err(OramError::Integrity(Some(k)))
+            } else if block.header.occupied {
+                Ok((k, Some(block.data.buf)))
+            } else {
+                Ok((k, None))
+            }
+        }))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = (self.oram.num_blocks() - self.next) as usize;
+        (remaining, Some(remaining))
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::OramBuilder;
+
+    #[test]
+    fn scan_in_key_order() {
+        let n: u64 = 16;
+        let mut oram = SqrtOram::new(n, 8).unwrap();
+        for i in (0..n).filter(|i| i % 3 != 0) {
+            oram.put(i, vec![i as u8]).unwrap();
+        }
+
+        let mut visited = Vec::new();
+        oram.scan(|k, data| visited.push((k, data))).unwrap();
+        let expected: Vec<_> = (0..n)
+            .map(|i| {
+                (
+                    i,
+                    if i % 3 != 0 {
+                        Some(vec![i as u8])
+                    } else {
+                        None
+                    },
+                )
+            })
+            .collect();
+        assert_eq!(visited, expected);
+        assert!(!oram.scanning);
+
+        let items: Vec<_> = oram.iter().unwrap().collect::<Result<_>>().unwrap();
+        assert_eq!(items, expected);
+        // the next access starts a new epoch
+        oram.put(0, vec![100]).unwrap();
+        assert!(!oram.scanning);
+        assert_eq!(oram.get(0).unwrap(), Some(vec![100]));
+    }
+
+    #[test]
+    fn scan_in_the_middle_of_an_epoch() {
+        let n: u64 = 16;
+        let mut oram = OramBuilder::new(n, 8).shelter_size(8).build().unwrap();
+        for i in 0..5 {
+            oram.put(i, vec![i as u8]).unwrap();
+        }
+        assert_eq!(oram.count, 5);
+
+        let mut occupied = 0;
+        oram.scan(|_, data| occupied += data.is_some() as u64)
+            .unwrap();
+        assert_eq!(occupied, 5);
+        assert_eq!(oram.count, 0);
+        for i in 0..5 {
+            assert_eq!(oram.get(i).unwrap(), Some(vec![i as u8]));
+        }
+    }
+}
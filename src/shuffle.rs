@@ -0,0 +1,228 @@
+// Copyright 2020 ADVANCA PTE. LTD.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The epoch shuffle with `sort::MelbourneShuffle`
+//!
+//! The cells of the shuffle are stored under keys of their own, which adds
+//! about three blocks of storage per block. Fillers are dummy blocks, which
+//! the permuted area holds none of. Every block is tagged with its new
+//! position, so that the permutation can be recovered from the blocks.
+//!
+//! Once all cells are written the journal says so, and a shuffle interrupted
+//! later is finished from the cells with some other permutation.
+
+use crate::cell::RefCell;
+use crate::journal::Journal;
+use crate::sort::MelbourneShuffle;
+use crate::vec::Vec;
+use crate::{serialize, Block, BlockCache, OramError, Result, SqrtOram};
+
+/// Prefix of the storage keys of the cells
+const SCRATCH_KEY: &[u8] = b"scratch";
+
+fn scratch_key(i: u64) -> Vec<u8> {
+    let mut key = SCRATCH_KEY.to_vec();
+    key.extend_from_slice(&i.to_be_bytes());
+    key
+}
+
+impl SqrtOram {
+    /// Shuffle the permuted area with a random permutation
+    pub(crate) fn melbourne_shuffle(&mut self) -> Result<()> {
+        let shuffle = MelbourneShuffle::new(self.dummy_range().end);
+        let permutation = shuffle.random_permutation(self.rng);
+        let headers = self.distribute(&shuffle, &permutation)?;
+        self.write_journal(&Journal::Distributed)?;
+        self.collect(&shuffle, Some(&permutation), Some(&headers))
+    }
+
+    /// Finish a shuffle interrupted after the distribution
+    pub(crate) fn collect_scratch(&mut self) -> Result<()> {
+        let shuffle = MelbourneShuffle::new(self.dummy_range().end);
+        self.collect(&shuffle, None, None)
+    }
+
+    /// Copy the permuted area into the cells, returning the headers written
+    fn distribute(
+        &mut self,
+        shuffle: &MelbourneShuffle,
+        permutation: &[u64],
+    ) -> Result<Vec<Option<BlockCache>>> {
+        let mut headers = vec![None; shuffle.scratch_len() as usize];
+        let oram = RefCell::new(self);
+        shuffle.try_distribute(
+            permutation,
+            |i, w| match w {
+                Some(_) => unreachable!("the distribution only reads the array"),
+                None => oram.borrow_mut().read_block(i).map(Some),
+            },
+            |i, w: Option<&Option<Block>>| {
+                let mut oram = oram.borrow_mut();
+                let block = match w.expect("the distribution only writes the cells") {
+                    Some(block) => block.clone(),
+                    None => Block::new_dummy(oram.block_size, oram.rng),
+                };
+                oram.write_scratch(i, &block)?;
+                headers[i as usize] = Some(block.header);
+                Ok(None)
+            },
+        )?;
+        Ok(headers)
+    }
+
+    /// Move the blocks from the cells to the permuted area, tagged with their
+    /// position
+    ///
+    /// The cells are checked against `headers` if given.
+    fn collect(
+        &mut self,
+        shuffle: &MelbourneShuffle,
+        permutation: Option<&[u64]>,
+        headers: Option<&[Option<BlockCache>]>,
+    ) -> Result<()> {
+        let oram = RefCell::new(self);
+        shuffle.try_collect(
+            permutation,
+            |i, w: Option<&Block>| {
+                let mut block = w.expect("the collection only writes the array").clone();
+                block.header.tag = i;
+                oram.borrow_mut().write_block(i, &block).map(|_| None)
+            },
+            |i, w| match w {
+                Some(_) => unreachable!("the collection only reads the cells"),
+                None => {
+                    let block = oram.borrow_mut().read_scratch(i)?;
+                    match headers {
+                        Some(headers) if headers[i as usize].as_ref() != Some(&block.header) => {
+                            Err(OramError::Integrity(None))
+                        }
+                        _ => Ok(Some(Some(block).filter(|b| !b.header.dummy))),
+                    }
+                }
+            },
+            || OramError::Integrity(None),
+        )
+    }
+
+    fn write_scratch(&mut self, i: u64, block: &Block) -> Result<()> {
+        self.db
+            .put(&scratch_key(i), &serialize(block).expect("serialize block"))
+    }
+
+    fn read_scratch(&mut self, i: u64) -> Result<Block> {
+        let data = self.db.get(&scratch_key(i))?;
+        Block::decode(i, data, self.block_size).map_err(|_| OramError::Integrity(None))
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{OramBuilder, ShuffleMode};
+
+    fn filled_oram(n: u64) -> SqrtOram {
+        let mut oram = OramBuilder::new(n, 8)
+            .shuffle_mode(ShuffleMode::Melbourne)
+            .build()
+            .unwrap();
+        for i in 0..n {
+            oram.put(i, vec![i as u8]).unwrap();
+        }
+        oram
+    }
+
+    fn check_contents(oram: &mut SqrtOram) {
+        for i in 0..oram.num_blocks() {
+            assert_eq!(oram.get(i).unwrap(), Some(vec![i as u8]));
+        }
+    }
+
+    #[test]
+    fn tag_blocks_with_their_position() {
+        let mut oram = filled_oram(30);
+        oram.rearrange().unwrap();
+        oram.permute().unwrap();
+        for i in 0..oram.dummy_range().end {
+            assert_eq!(oram.cache[i as usize].tag, i);
+        }
+        check_contents(&mut oram);
+    }
+
+    #[test]
+    fn detect_replaced_cell() {
+        let mut oram = filled_oram(30);
+        oram.rearrange().unwrap();
+        let shuffle = MelbourneShuffle::new(oram.dummy_range().end);
+        let permutation = shuffle.random_permutation(oram.rng);
+        let headers = oram.distribute(&shuffle, &permutation).unwrap();
+
+        let filler = Block::new_dummy(8, oram.rng);
+        let cell = (0..shuffle.scratch_len())
+            .find(|&i| headers[i as usize] != Some(filler.header.clone()))
+            .unwrap();
+        oram.write_scratch(cell, &filler).unwrap();
+        assert_eq!(
+            oram.collect(&shuffle, Some(&permutation), Some(&headers)),
+            Err(OramError::Integrity(None))
+        );
+    }
+
+    #[test]
+    fn finish_interrupted_shuffle() {
+        let mut oram = filled_oram(30);
+        oram.rearrange().unwrap();
+        let shuffle = MelbourneShuffle::new(oram.dummy_range().end);
+        let permutation = shuffle.random_permutation(oram.rng);
+        oram.distribute(&shuffle, &permutation).unwrap();
+        oram.write_journal(&Journal::Distributed).unwrap();
+
+        // crash in the collection, after a block was overwritten
+        let block = oram.read_block(1).unwrap();
+        oram.write_block(0, &block).unwrap();
+
+        oram.cache.iter_mut().for_each(|c| *c = Default::default());
+        oram.recover().unwrap();
+        oram.permute().unwrap();
+        check_contents(&mut oram);
+    }
+
+    #[test]
+    fn reject_tampered_cells_when_finishing() {
+        for extra in &[false, true] {
+            let mut oram = filled_oram(30);
+            oram.rearrange().unwrap();
+            let shuffle = MelbourneShuffle::new(oram.dummy_range().end);
+            let permutation = shuffle.random_permutation(oram.rng);
+            let headers = oram.distribute(&shuffle, &permutation).unwrap();
+            oram.write_journal(&Journal::Distributed).unwrap();
+
+            let real = (0..shuffle.scratch_len())
+                .find(|&i| matches!(headers[i as usize], Some(ref h) if !h.dummy))
+                .unwrap();
+            if *extra {
+                // a real block in a filler slot makes its bucket overflow
+                let filler = (0..shuffle.scratch_len())
+                    .find(|&i| matches!(headers[i as usize], Some(ref h) if h.dummy))
+                    .unwrap();
+                let block = oram.read_scratch(real).unwrap();
+                oram.write_scratch(filler, &block).unwrap();
+            } else {
+                // a missing block leaves a hole in its bucket
+                oram.write_scratch(real, &Block::new_dummy(8, oram.rng))
+                    .unwrap();
+            }
+            assert_eq!(oram.collect_scratch(), Err(OramError::Integrity(None)));
+        }
+    }
+}
@@ -15,14 +15,96 @@
 #[cfg(feature = "sgx")]
 use sgx_tstd::{self as std, prelude::v1::*};
 
+use std::convert::Infallible;
 use std::marker::PhantomData;
 use std::ops::Range;
 
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use crate::ct::select_pair;
+pub use crate::ct::{ct_eq, ct_less, Choice, ConditionallySelectable};
+
+/// A sorting network: a fixed schedule of comparators
+///
+/// Both networks are due to Batcher. Their comparators do not depend on the
+/// data, so running one through an access function reveals nothing but the
+/// number of elements.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Network {
+    /// Odd-even mergesort, the network with fewer comparators
+    OddEvenMerge,
+    /// Bitonic sort, the network with the simpler, fully regular schedule
+    Bitonic,
+}
+
+impl Network {
+    /// Visit the layers of comparators sorting `n` elements, in order
+    ///
+    /// A comparator `(i, j)` has `i < j < n` and puts the smaller element at
+    /// `i`. The comparators of one layer touch distinct elements, so they can
+    /// be run in any order. Lengths other than powers of two are handled as if
+    /// padded with elements larger than all others, whose comparators are left
+    /// out. Visiting stops at the first error returned by `f`.
+    pub fn try_for_each_layer<E, F>(self, n: u64, mut f: F) -> Result<(), E>
+    where
+        F: FnMut(&[(u64, u64)]) -> Result<(), E>,
+    {
+        let high = n.next_power_of_two();
+        let mut layer = Vec::new();
+        match self {
+            Network::OddEvenMerge => {
+                // merge sorted runs of length `p`, comparing elements `k` apart
+                let mut p = 1;
+                while p < high {
+                    let mut k = p;
+                    while k > 0 {
+                        layer.clear();
+                        let mut j = k % p;
+                        while j + k < high {
+                            for i in j..j + k {
+                                if i / (2 * p) == (i + k) / (2 * p) && i + k < n {
+                                    layer.push((i, i + k));
+                                }
+                            }
+                            j += 2 * k;
+                        }
+                        f(&layer)?;
+                        k /= 2;
+                    }
+                    p *= 2;
+                }
+            }
+            Network::Bitonic => {
+                // the first layer of every merge compares mirrored elements,
+                // so all comparators sort in ascending order
+                let mut k = 2;
+                while k <= high {
+                    let mut j = k / 2;
+                    while j > 0 {
+                        layer.clear();
+                        for i in 0..n {
+                            let l = if j == k / 2 { i ^ (k - 1) } else { i ^ j };
+                            if i < l && l < n {
+                                layer.push((i, l));
+                            }
+                        }
+                        f(&layer)?;
+                        j /= 2;
+                    }
+                    k *= 2;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
 /// Use Batcher's odd-even mergesort algorithm to sort an external array (or array-like structure)
 ///
 /// # Arguments
 ///
-/// `range` specifies a 0-indexed range starting from 0 in the external array.
+/// `range` specifies the positions to sort in the external array.
 /// `cmp` is the compare function used to sort the element in ascending order.
 /// `access` is the access function to read element from or write to the external array.
 ///
@@ -31,107 +113,836 @@ use std::ops::Range;
 /// ```
 /// let mut v = vec![4, 3, 2, 1, 0];
 /// let sorted = vec![0, 1, 2, 3, 4];
-/// oram::sort::odd_even_mergesort(0..v.len(), |x: &i32, y: &i32| x<y, |i: usize, w: Option<&i32>| match w {
-///     Some(x) => { v[i] = *x; None } // It's a write
-///     None => Some(v[i])  // It's a read
+/// oram::sort::odd_even_mergesort(0..v.len() as u64, |x: &i32, y: &i32| x<y, |i: u64, w: Option<&i32>| match w {
+///     Some(x) => { v[i as usize] = *x; None } // It's a write
+///     None => Some(v[i as usize])  // It's a read
 /// });
 /// assert_eq!(v, sorted);
 /// ```
-pub fn odd_even_mergesort<T, C, A>(range: Range<usize>, cmp: C, access: A)
+pub fn odd_even_mergesort<T, C, A>(range: Range<u64>, cmp: C, access: A)
+where
+    C: Fn(&T, &T) -> bool,
+    A: FnMut(u64, Option<&T>) -> Option<T>,
+{
+    sort_with(Network::OddEvenMerge, range, cmp, access)
+}
+
+/// Same as `odd_even_mergesort`, but with a fallible `access` function
+///
+/// Sorting stops at the first error returned by `access`, which is passed on
+/// to the caller. The external array may be left partially sorted.
+pub fn try_odd_even_mergesort<T, E, C, A>(range: Range<u64>, cmp: C, access: A) -> Result<(), E>
+where
+    C: Fn(&T, &T) -> bool,
+    A: FnMut(u64, Option<&T>) -> Result<Option<T>, E>,
+{
+    try_sort_with(Network::OddEvenMerge, range, cmp, access)
+}
+
+/// Use Batcher's bitonic sort to sort an external array, see `odd_even_mergesort`
+///
+/// # Examples
+///
+/// ```
+/// let mut v = vec![4, 3, 2, 1, 0];
+/// oram::sort::bitonic_sort(0..v.len() as u64, |x: &i32, y: &i32| x<y, |i: u64, w: Option<&i32>| match w {
+///     Some(x) => { v[i as usize] = *x; None }
+///     None => Some(v[i as usize])
+/// });
+/// assert_eq!(v, vec![0, 1, 2, 3, 4]);
+/// ```
+pub fn bitonic_sort<T, C, A>(range: Range<u64>, cmp: C, access: A)
+where
+    C: Fn(&T, &T) -> bool,
+    A: FnMut(u64, Option<&T>) -> Option<T>,
+{
+    sort_with(Network::Bitonic, range, cmp, access)
+}
+
+/// Same as `bitonic_sort`, but with a fallible `access` function
+pub fn try_bitonic_sort<T, E, C, A>(range: Range<u64>, cmp: C, access: A) -> Result<(), E>
 where
     C: Fn(&T, &T) -> bool,
-    A: FnMut(usize, Option<&T>) -> Option<T>,
+    A: FnMut(u64, Option<&T>) -> Result<Option<T>, E>,
 {
-    BatcherSort::new(range, cmp, access).sort();
+    try_sort_with(Network::Bitonic, range, cmp, access)
 }
 
-struct BatcherSort<T, C, A>
+/// Sort an external array with the given network, see `odd_even_mergesort`
+pub fn sort_with<T, C, A>(network: Network, range: Range<u64>, cmp: C, mut access: A)
 where
     C: Fn(&T, &T) -> bool,
-    A: FnMut(usize, Option<&T>) -> Option<T>,
+    A: FnMut(u64, Option<&T>) -> Option<T>,
 {
-    range: Range<usize>,
+    let result = try_sort_with(network, range, cmp, |i, w| {
+        Ok::<_, Infallible>(access(i, w))
+    });
+    match result {
+        Ok(()) => {}
+        Err(e) => match e {},
+    }
+}
+
+/// Same as `sort_with`, but with a fallible `access` function
+pub fn try_sort_with<T, E, C, A>(
+    network: Network,
+    range: Range<u64>,
     cmp: C,
     access: A,
-    phantom: PhantomData<T>,
+) -> Result<(), E>
+where
+    C: Fn(&T, &T) -> bool,
+    A: FnMut(u64, Option<&T>) -> Result<Option<T>, E>,
+{
+    BatcherSort::new(network, range, cmp, access).sort()
 }
 
-impl<T, C, A> BatcherSort<T, C, A>
+/// Sort an external array with the given network, keeping equal elements in
+/// their original order, see `odd_even_mergesort`
+///
+/// Trusted memory holds the original position of every element, which breaks
+/// ties between elements equal under `cmp`.
+pub fn stable_sort_with<T, C, A>(network: Network, range: Range<u64>, cmp: C, mut access: A)
 where
     C: Fn(&T, &T) -> bool,
-    A: FnMut(usize, Option<&T>) -> Option<T>,
+    A: FnMut(u64, Option<&T>) -> Option<T>,
 {
-    fn new(range: Range<usize>, cmp: C, access: A) -> Self {
-        BatcherSort {
-            range,
-            cmp,
-            access,
-            phantom: PhantomData,
+    let result = try_stable_sort_with(network, range, cmp, |i, w| {
+        Ok::<_, Infallible>(access(i, w))
+    });
+    match result {
+        Ok(()) => {}
+        Err(e) => match e {},
+    }
+}
+
+/// Same as `stable_sort_with`, but with a fallible `access` function
+pub fn try_stable_sort_with<T, E, C, A>(
+    network: Network,
+    range: Range<u64>,
+    cmp: C,
+    mut access: A,
+) -> Result<(), E>
+where
+    C: Fn(&T, &T) -> bool,
+    A: FnMut(u64, Option<&T>) -> Result<Option<T>, E>,
+{
+    let start = range.start;
+    let mut origins = range.clone().collect::<Vec<u64>>();
+    try_sort_with(
+        network,
+        range,
+        // both comparisons are always made, so ties do not show
+        |(i, x): &(u64, T), (j, y): &(u64, T)| cmp(x, y) | (!cmp(y, x) & (i < j)),
+        |k, w| {
+            let origin = &mut origins[(k - start) as usize];
+            match w {
+                Some((i, x)) => {
+                    *origin = *i;
+                    access(k, Some(x)).map(|_| None)
+                }
+                None => Ok(access(k, None)?.map(|x| (*origin, x))),
+            }
+        },
+    )
+}
+
+/// Same as `try_sort_with`, but running the comparators of each layer on the
+/// rayon thread pool
+///
+/// The external array is accessed from several threads at once: `read` reads
+/// an element and `write(i, x, j, y)` writes the two elements of one comparator.
+/// The layers still run one after the other, so the access pattern is that of
+/// `try_sort_with()`, up to the order within a layer.
+///
+/// # Examples
+///
+/// ```
+/// use oram::sort::{try_par_sort_with, Network};
+/// use std::sync::Mutex;
+///
+/// let v = Mutex::new(vec![4, 3, 2, 1, 0]);
+/// try_par_sort_with(
+///     Network::Bitonic,
+///     0..5,
+///     |x: &i32, y: &i32| x < y,
+///     |i| Ok::<_, ()>(v.lock().unwrap()[i as usize]),
+///     |i, x, j, y| {
+///         let mut v = v.lock().unwrap();
+///         v[i as usize] = *x;
+///         v[j as usize] = *y;
+///         Ok(())
+///     },
+/// )
+/// .unwrap();
+/// assert_eq!(v.into_inner().unwrap(), vec![0, 1, 2, 3, 4]);
+/// ```
+#[cfg(feature = "parallel")]
+pub fn try_par_sort_with<T, E, C, R, W>(
+    network: Network,
+    range: Range<u64>,
+    cmp: C,
+    read: R,
+    write: W,
+) -> Result<(), E>
+where
+    E: Send,
+    C: Fn(&T, &T) -> bool + Sync,
+    R: Fn(u64) -> Result<T, E> + Sync,
+    W: Fn(u64, &T, u64, &T) -> Result<(), E> + Sync,
+{
+    let start = range.start;
+    network.try_for_each_layer(range.end.saturating_sub(start), |layer| {
+        layer.par_iter().try_for_each(|&(a, b)| {
+            let (a, b) = (start + a, start + b);
+            let a_obj = read(a)?;
+            let b_obj = read(b)?;
+            let (first, second) = select_pair(&a_obj, &b_obj, !Choice::from(cmp(&a_obj, &b_obj)));
+            write(a, first, b, second)
+        })
+    })
+}
+
+/// Sort a slice in trusted memory with the given network
+///
+/// Elements are swapped with constant-time selects, so neither the memory
+/// accesses nor the branches taken depend on the order of the elements.
+///
+/// # Examples
+///
+/// ```
+/// use oram::sort::{sort_in_memory, Network};
+///
+/// let mut v = vec![4u32, 3, 2, 1, 0];
+/// sort_in_memory(Network::OddEvenMerge, &mut v, |x, y| x < y);
+/// assert_eq!(v, vec![0, 1, 2, 3, 4]);
+/// ```
+pub fn sort_in_memory<T, C>(network: Network, v: &mut [T], cmp: C)
+where
+    T: ConditionallySelectable,
+    C: Fn(&T, &T) -> bool,
+{
+    let result = network.try_for_each_layer(v.len() as u64, |layer| {
+        for &(a, b) in layer {
+            let (low, high) = v.split_at_mut(b as usize);
+            let (x, y) = (&mut low[a as usize], &mut high[0]);
+            let swap = !Choice::from(cmp(x, y));
+            T::conditional_swap(x, y, swap);
+        }
+        Ok::<_, Infallible>(())
+    });
+    match result {
+        Ok(()) => {}
+        Err(e) => match e {},
+    }
+}
+
+/// Sort a slice with odd-even mergesort, see `odd_even_mergesort`
+///
+/// Like `odd_even_mergesort()`, the positions accessed do not depend on the
+/// elements. Every comparator clones both elements; for constant-time swaps
+/// of elements see `sort_in_memory()`.
+///
+/// # Examples
+///
+/// ```
+/// let mut v = vec!["d", "c", "b", "a"];
+/// oram::sort::sort_slice(&mut v, |x, y| x < y);
+/// assert_eq!(v, vec!["a", "b", "c", "d"]);
+/// ```
+pub fn sort_slice<T, C>(v: &mut [T], cmp: C)
+where
+    T: Clone,
+    C: Fn(&T, &T) -> bool,
+{
+    sort_with(
+        Network::OddEvenMerge,
+        0..v.len() as u64,
+        cmp,
+        slice_access(v),
+    )
+}
+
+/// Sort a slice by the key extracted by `key`, see `sort_slice`
+///
+/// # Examples
+///
+/// ```
+/// let mut v = vec![(3, "c"), (1, "a"), (2, "b")];
+/// oram::sort::sort_slice_by_key(&mut v, |&(k, _)| k);
+/// assert_eq!(v, vec![(1, "a"), (2, "b"), (3, "c")]);
+/// ```
+pub fn sort_slice_by_key<T, K, F>(v: &mut [T], key: F)
+where
+    T: Clone,
+    K: PartialOrd,
+    F: Fn(&T) -> K,
+{
+    sort_slice(v, |x, y| key(x) < key(y))
+}
+
+/// Sort a slice, keeping equal elements in their original order, see
+/// `stable_sort_with`
+pub fn stable_sort_slice<T, C>(v: &mut [T], cmp: C)
+where
+    T: Clone,
+    C: Fn(&T, &T) -> bool,
+{
+    stable_sort_with(
+        Network::OddEvenMerge,
+        0..v.len() as u64,
+        cmp,
+        slice_access(v),
+    )
+}
+
+/// Sort a slice by the key extracted by `key`, keeping elements with equal
+/// keys in their original order
+///
+/// # Examples
+///
+/// ```
+/// let mut v = vec![(2, "x"), (1, "y"), (2, "a"), (1, "b")];
+/// oram::sort::stable_sort_slice_by_key(&mut v, |&(k, _)| k);
+/// assert_eq!(v, vec![(1, "y"), (1, "b"), (2, "x"), (2, "a")]);
+/// ```
+pub fn stable_sort_slice_by_key<T, K, F>(v: &mut [T], key: F)
+where
+    T: Clone,
+    K: PartialOrd,
+    F: Fn(&T) -> K,
+{
+    stable_sort_slice(v, |x, y| key(x) < key(y))
+}
+
+/// An access function reading and writing the elements of a slice
+fn slice_access<T: Clone>(v: &mut [T]) -> impl FnMut(u64, Option<&T>) -> Option<T> + '_ {
+    move |i, w| match w {
+        Some(x) => {
+            v[i as usize] = x.clone();
+            None
+        }
+        None => Some(v[i as usize].clone()),
+    }
+}
+
+/// Sort an external array holding at most `memory` elements in trusted memory
+/// at once, see `odd_even_mergesort`
+///
+/// The range is split into chunks of `memory / 2` elements, each sorted in
+/// trusted memory with `sort_in_memory()`. The network then runs over the
+/// chunks, every comparator loading two chunks and putting the smaller half of
+/// their elements in the first one. With `c` chunks this takes about
+/// `n log^2 c` accesses instead of the `n log^2 n` of `odd_even_mergesort()`.
+///
+/// Every step reads some elements, then writes all of them back, so a caller
+/// can buffer the writes of a step and store them at once. The accesses depend
+/// on the length of the range and `memory` alone.
+///
+/// # Examples
+///
+/// ```
+/// use oram::sort::{sort_in_chunks, Network};
+///
+/// let mut v = vec![9u32, 8, 7, 6, 5, 4, 3, 2, 1, 0];
+/// sort_in_chunks(Network::OddEvenMerge, 0..10, 4, |x, y| x < y, |i: u64, w: Option<&u32>| match w {
+///     Some(x) => { v[i as usize] = *x; None }
+///     None => Some(v[i as usize])
+/// });
+/// assert_eq!(v, (0..10).collect::<Vec<_>>());
+/// ```
+pub fn sort_in_chunks<T, C, A>(
+    network: Network,
+    range: Range<u64>,
+    memory: u64,
+    cmp: C,
+    mut access: A,
+) where
+    T: ConditionallySelectable,
+    C: Fn(&T, &T) -> bool,
+    A: FnMut(u64, Option<&T>) -> Option<T>,
+{
+    let result = try_sort_in_chunks(network, range, memory, cmp, |i, w| {
+        Ok::<_, Infallible>(access(i, w))
+    });
+    match result {
+        Ok(()) => {}
+        Err(e) => match e {},
+    }
+}
+
+/// Same as `sort_in_chunks`, but with a fallible `access` function
+pub fn try_sort_in_chunks<T, E, C, A>(
+    network: Network,
+    range: Range<u64>,
+    memory: u64,
+    cmp: C,
+    mut access: A,
+) -> Result<(), E>
+where
+    T: ConditionallySelectable,
+    C: Fn(&T, &T) -> bool,
+    A: FnMut(u64, Option<&T>) -> Result<Option<T>, E>,
+{
+    assert!(memory >= 2, "memory must hold at least two elements");
+    let len = range.end.saturating_sub(range.start);
+    let chunk_len = memory / 2;
+    let chunks = match len % chunk_len {
+        0 => len / chunk_len,
+        _ => len / chunk_len + 1,
+    };
+    let chunk = |c: u64| {
+        let start = range.start + c * chunk_len;
+        start..range.end.min(start + chunk_len)
+    };
+
+    // sort the elements of the given chunks together and write them back
+    let mut sort_chunks = |chunks: &[Range<u64>]| {
+        let mut v = Vec::new();
+        for i in chunks.iter().cloned().flatten() {
+            v.push(access(i, None)?.expect("read operation"));
+        }
+        sort_in_memory(network, &mut v, &cmp);
+        for (i, x) in chunks.iter().cloned().flatten().zip(v.iter()) {
+            access(i, Some(x))?;
+        }
+        Ok(())
+    };
+
+    for c in 0..chunks {
+        sort_chunks(&[chunk(c)])?;
+    }
+    // a network over sorted chunks, with comparators merging two of them,
+    // sorts all elements; the last chunk may be shorter, as if padded with
+    // elements larger than all others, which never leave it
+    network.try_for_each_layer(chunks, |layer| {
+        layer
+            .iter()
+            .try_for_each(|&(a, b)| sort_chunks(&[chunk(a), chunk(b)]))
+    })
+}
+
+/// Move the elements of an external array accepted by `keep` to the front of
+/// `range`, keeping their order, and return how many there are
+///
+/// This is Goodrich's order-preserving compaction, in O(n log n) accesses
+/// instead of the O(n log^2 n) of a sort. Each kept element is moved towards
+/// the front by the number of dropped elements before it, one bit of that
+/// distance per pass, and every pass moves all elements the same distance.
+/// The accesses depend on the length alone, so which elements are kept does
+/// not show. What is left after the kept elements is unspecified.
+///
+/// Trusted memory holds a distance per element.
+///
+/// # Examples
+///
+/// ```
+/// let mut v = vec![1, 10, 2, 20, 3];
+/// let kept = oram::sort::compact(0..v.len() as u64, |x: &i32| *x < 10, |i: u64, w: Option<&i32>| match w {
+///     Some(x) => { v[i as usize] = *x; None }
+///     None => Some(v[i as usize])
+/// });
+/// assert_eq!(kept, 3);
+/// assert_eq!(&v[..3], &[1, 2, 3]);
+/// ```
+pub fn compact<T, K, A>(range: Range<u64>, keep: K, mut access: A) -> u64
+where
+    K: Fn(&T) -> bool,
+    A: FnMut(u64, Option<&T>) -> Option<T>,
+{
+    let result = try_compact(range, keep, |i, w| Ok::<_, Infallible>(access(i, w)));
+    match result {
+        Ok(kept) => kept,
+        Err(e) => match e {},
+    }
+}
+
+/// Same as `compact`, but with a fallible `access` function
+pub fn try_compact<T, E, K, A>(range: Range<u64>, keep: K, mut access: A) -> Result<u64, E>
+where
+    K: Fn(&T) -> bool,
+    A: FnMut(u64, Option<&T>) -> Result<Option<T>, E>,
+{
+    let n = range.end.saturating_sub(range.start);
+    let start = range.start;
+
+    // whether each element is still to be moved, and how far
+    let mut labels = Vec::with_capacity(n as usize);
+    let mut dropped = 0u64;
+    for i in start..range.end {
+        let kept = keep(&access(i, None)?.expect("read operation"));
+        labels.push((
+            kept,
+            u64::conditional_select(&0, &dropped, Choice::from(kept)),
+        ));
+        dropped += u64::from(!kept);
+    }
+
+    let mut shift = 1;
+    let mut bit = 0;
+    while shift < n {
+        for i in shift..n {
+            let (to, from) = ((i - shift) as usize, i as usize);
+            let (pending, distance) = labels[from];
+            let moving = Choice::from(pending) & Choice::from(((distance >> bit) & 1) as u8);
+
+            let a = access(start + i - shift, None)?.expect("read operation");
+            let b = access(start + i, None)?.expect("read operation");
+            access(start + i - shift, Some(select_pair(&a, &b, moving).0))?;
+
+            let label = <(bool, u64)>::conditional_select(&labels[to], &labels[from], moving);
+            labels[to] = label;
+            labels[from].0.conditional_assign(&false, moving);
+        }
+        shift <<= 1;
+        bit += 1;
+    }
+    Ok(n - dropped)
+}
+
+/// Elements expected in a cell of the Melbourne shuffle
+const MELBOURNE_LOAD: u64 = 16;
+
+/// The Melbourne shuffle, an oblivious random permutation with O(n) accesses
+///
+/// The array is split into buckets of about `sqrt(16 n)` elements. The
+/// distribution phase reads one bucket at a time and writes a cell of
+/// `3 * 16` slots per destination bucket to a scratch array: the elements
+/// headed there, then fillers. The collection phase reads the cells of one
+/// destination bucket at a time and writes its elements in place. Cells have a
+/// fixed size, so the accesses depend on the length alone. A permutation
+/// overflowing a cell is redrawn before any access.
+///
+/// Trusted memory holds the permutation and the cells of one bucket, which
+/// are about `3 sqrt(16 n)` elements.
+///
+/// # Examples
+///
+/// ```
+/// use oram::sort::MelbourneShuffle;
+///
+/// let mut v = (0..100).collect::<Vec<u64>>();
+/// let mut scratch = vec![None; 0];
+/// let shuffle = MelbourneShuffle::new(v.len() as u64);
+/// scratch.resize(shuffle.scratch_len() as usize, None);
+///
+/// let permutation = shuffle.random_permutation(|buf| buf.iter_mut().for_each(|b| *b = 7));
+/// shuffle.shuffle(
+///     &permutation,
+///     |i, w: Option<&u64>| match w {
+///         Some(x) => { v[i as usize] = *x; None }
+///         None => Some(v[i as usize]),
+///     },
+///     |i, w: Option<&Option<u64>>| match w {
+///         Some(x) => { scratch[i as usize] = *x; None }
+///         None => Some(scratch[i as usize]),
+///     },
+/// );
+/// for (i, &p) in permutation.iter().enumerate() {
+///     assert_eq!(v[p as usize], i as u64);
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MelbourneShuffle {
+    /// Number of elements
+    n: u64,
+    /// Number of elements in a bucket, but the last
+    chunk: u64,
+    /// Number of buckets
+    buckets: u64,
+    /// Number of slots in a cell
+    padding: u64,
+}
+
+impl MelbourneShuffle {
+    /// Shuffle `n` elements
+    pub fn new(n: u64) -> Self {
+        let chunk = (((MELBOURNE_LOAD * n) as f64).sqrt().ceil() as u64)
+            .min(n)
+            .max(1);
+        let buckets = match n % chunk {
+            0 => n / chunk,
+            _ => n / chunk + 1,
+        };
+        MelbourneShuffle {
+            n,
+            chunk,
+            buckets,
+            padding: (3 * MELBOURNE_LOAD).min(chunk),
+        }
+    }
+
+    /// Positions of bucket `b`
+    fn bucket(&self, b: u64) -> Range<u64> {
+        b * self.chunk..((b + 1) * self.chunk).min(self.n)
+    }
+
+    /// Length of the scratch array
+    pub fn scratch_len(&self) -> u64 {
+        self.buckets * self.buckets * self.padding
+    }
+
+    /// Position in the scratch array of slot `s` of the cell for elements from
+    /// bucket `from` to bucket `to`
+    fn slot(&self, to: u64, from: u64, s: u64) -> u64 {
+        (to * self.buckets + from) * self.padding + s
+    }
+
+    /// Draw a uniformly random permutation of `0..n` with no overflowing cell
+    ///
+    /// Element `i` is moved to position `permutation[i]`. `rng` fills a buffer
+    /// with random bytes.
+    pub fn random_permutation<R: FnMut(&mut [u8])>(&self, mut rng: R) -> Vec<u64> {
+        loop {
+            let mut permutation = (0..self.n).collect::<Vec<u64>>();
+            // Fisher-Yates, with rejection sampling for uniform indices
+            for i in (1..self.n).rev() {
+                let bound = u64::MAX - u64::MAX % (i + 1);
+                let j = loop {
+                    let mut buf = [0u8; 8];
+                    rng(&mut buf);
+                    let r = u64::from_le_bytes(buf);
+                    if r < bound {
+                        break r % (i + 1);
+                    }
+                };
+                permutation.swap(i as usize, j as usize);
+            }
+            if self.fits(&permutation) {
+                return permutation;
+            }
         }
     }
 
-    fn sort(&mut self) {
-        assert_eq!(self.range.start, 0, "range must start from 0");
-        let high = self.range.end.next_power_of_two();
+    /// Whether no cell receives more than `padding` elements
+    fn fits(&self, permutation: &[u64]) -> bool {
+        let buckets = self.buckets;
+        let mut load = vec![0; buckets as usize];
+        (0..buckets).all(|from| {
+            load.iter_mut().for_each(|l| *l = 0);
+            self.bucket(from).all(|i| {
+                let to = (permutation[i as usize] / self.chunk) as usize;
+                load[to] += 1;
+                load[to] <= self.padding
+            })
+        })
+    }
+
+    /// Move element `i` of an external array to position `permutation[i]`
+    ///
+    /// `access` reads from or writes to the array like in `odd_even_mergesort`.
+    /// `scratch` does the same for a scratch array of `scratch_len()` slots,
+    /// where `None` is a filler. Fillers have to look like elements on the
+    /// storage. `permutation` has to come from `random_permutation()`.
+    pub fn shuffle<T, A, S>(&self, permutation: &[u64], mut access: A, mut scratch: S)
+    where
+        T: Clone,
+        A: FnMut(u64, Option<&T>) -> Option<T>,
+        S: FnMut(u64, Option<&Option<T>>) -> Option<Option<T>>,
+    {
+        let result = self.try_shuffle(
+            permutation,
+            |i, w| Ok::<_, Infallible>(access(i, w)),
+            |i, w| Ok(scratch(i, w)),
+        );
+        match result {
+            Ok(()) => {}
+            Err(e) => match e {},
+        }
+    }
 
-        self.odd_even_merge_sort(self.range.start, high);
+    /// Same as `shuffle`, but with fallible access functions
+    ///
+    /// The array stays unchanged if an error occurs in the distribution phase.
+    ///
+    /// # Panics
+    ///
+    /// If `scratch` does not read back the elements written to it.
+    pub fn try_shuffle<T, E, A, S>(
+        &self,
+        permutation: &[u64],
+        mut access: A,
+        mut scratch: S,
+    ) -> Result<(), E>
+    where
+        T: Clone,
+        A: FnMut(u64, Option<&T>) -> Result<Option<T>, E>,
+        S: FnMut(u64, Option<&Option<T>>) -> Result<Option<Option<T>>, E>,
+    {
+        self.try_distribute(permutation, &mut access, &mut scratch)?;
+        self.try_collect(Some(permutation), access, scratch, || {
+            panic!("the scratch array does not hold the elements written to it")
+        })
     }
 
-    fn odd_even_merge_sort(&mut self, low: usize, high: usize) {
-        if high - low > 1 {
-            let m = (high - low) >> 1;
-            self.odd_even_merge_sort(low, low + m);
-            self.odd_even_merge_sort(low + m, high);
-            self.odd_even_merge(low, high, 1);
+    /// Copy the elements into the cells of the scratch array
+    pub(crate) fn try_distribute<T, E, A, S>(
+        &self,
+        permutation: &[u64],
+        mut access: A,
+        mut scratch: S,
+    ) -> Result<(), E>
+    where
+        T: Clone,
+        A: FnMut(u64, Option<&T>) -> Result<Option<T>, E>,
+        S: FnMut(u64, Option<&Option<T>>) -> Result<Option<Option<T>>, E>,
+    {
+        assert_eq!(
+            permutation.len() as u64,
+            self.n,
+            "permutation of another length"
+        );
+        let buckets = self.buckets;
+        for from in 0..buckets {
+            let mut cells = (0..buckets).map(|_| Vec::new()).collect::<Vec<_>>();
+            for i in self.bucket(from) {
+                let x = access(i, None)?.expect("read operation");
+                cells[(permutation[i as usize] / self.chunk) as usize].push(x);
+            }
+            for (to, cell) in cells.into_iter().enumerate() {
+                let mut cell = cell.into_iter();
+                for s in 0..self.padding {
+                    scratch(self.slot(to as u64, from, s), Some(&cell.next()))?;
+                }
+            }
         }
+        Ok(())
     }
 
-    fn odd_even_merge(&mut self, low: usize, high: usize, d: usize) {
-        if (high - low) > 2 * d {
-            self.odd_even_merge(low, high, 2 * d);
-            self.odd_even_merge(low + d, high, 2 * d);
-            for i in (low + d..high - d).step_by(2 * d) {
-                if i + d < self.range.end {
-                    self.compare_and_swap(i, i + d);
+    /// Move the elements from the cells of the scratch array to their positions
+    ///
+    /// Without `permutation`, the elements of each bucket are written in the
+    /// order of the cells, which finishes an interrupted shuffle with some
+    /// permutation. The error of `malformed` is returned if the cells do not
+    /// hold exactly the elements of a bucket; the bucket is then not written.
+    pub(crate) fn try_collect<T, E, A, S, M>(
+        &self,
+        permutation: Option<&[u64]>,
+        mut access: A,
+        mut scratch: S,
+        malformed: M,
+    ) -> Result<(), E>
+    where
+        T: Clone,
+        A: FnMut(u64, Option<&T>) -> Result<Option<T>, E>,
+        S: FnMut(u64, Option<&Option<T>>) -> Result<Option<Option<T>>, E>,
+        M: Fn() -> E,
+    {
+        let buckets = self.buckets;
+        for to in 0..buckets {
+            let positions = self.bucket(to);
+            let mut elements = vec![None; (positions.end - positions.start) as usize];
+            let mut filled = 0;
+            for from in 0..buckets {
+                // the cell holds the elements of bucket `from` headed here, in order
+                let mut sources = self.bucket(from).filter(|&i| match permutation {
+                    Some(p) => p[i as usize] / self.chunk == to,
+                    None => false,
+                });
+                for s in 0..self.padding {
+                    if let Some(x) = scratch(self.slot(to, from, s), None)?.expect("read operation")
+                    {
+                        let position = match permutation {
+                            Some(p) => match sources.next() {
+                                Some(i) => p[i as usize],
+                                None => return Err(malformed()),
+                            },
+                            None => positions.start + filled,
+                        };
+                        match elements.get_mut((position - positions.start) as usize) {
+                            Some(element) => *element = Some(x),
+                            None => return Err(malformed()),
+                        }
+                        filled += 1;
+                    }
                 }
             }
-        } else if low + d < self.range.end {
-            self.compare_and_swap(low, low + d);
+            let elements = match elements.into_iter().collect::<Option<Vec<T>>>() {
+                Some(elements) => elements,
+                None => return Err(malformed()),
+            };
+            for (i, x) in positions.zip(elements) {
+                access(i, Some(&x))?;
+            }
         }
+        Ok(())
     }
+}
 
-    fn compare_and_swap(&mut self, a: usize, b: usize) {
-        let a_obj = (self.access)(a, None).expect("read operation");
-        let b_obj = (self.access)(b, None).expect("read operation");
+struct BatcherSort<T, E, C, A>
+where
+    C: Fn(&T, &T) -> bool,
+    A: FnMut(u64, Option<&T>) -> Result<Option<T>, E>,
+{
+    network: Network,
+    range: Range<u64>,
+    cmp: C,
+    access: A,
+    phantom: PhantomData<(T, E)>,
+}
 
-        if !(self.cmp)(&a_obj, &b_obj) {
-            (self.access)(a, Some(&b_obj));
-            (self.access)(b, Some(&a_obj));
+impl<T, E, C, A> BatcherSort<T, E, C, A>
+where
+    C: Fn(&T, &T) -> bool,
+    A: FnMut(u64, Option<&T>) -> Result<Option<T>, E>,
+{
+    fn new(network: Network, range: Range<u64>, cmp: C, access: A) -> Self {
+        BatcherSort {
+            network,
+            range,
+            cmp,
+            access,
+            phantom: PhantomData,
         }
     }
+
+    fn sort(&mut self) -> Result<(), E> {
+        let start = self.range.start;
+        let len = self.range.end.saturating_sub(start);
+        self.network.try_for_each_layer(len, |layer| {
+            layer
+                .iter()
+                .try_for_each(|&(a, b)| self.compare_and_swap(start + a, start + b))
+        })
+    }
+
+    /// Put the smaller of elements `a` and `b` at `a`
+    ///
+    /// Both elements are written back whether they are swapped or not, and the
+    /// order is picked without branching, so the comparison does not show.
+    fn compare_and_swap(&mut self, a: u64, b: u64) -> Result<(), E> {
+        let a_obj = (self.access)(a, None)?.expect("read operation");
+        let b_obj = (self.access)(b, None)?.expect("read operation");
+
+        let swap = !Choice::from((self.cmp)(&a_obj, &b_obj));
+        let (first, second) = select_pair(&a_obj, &b_obj, swap);
+        (self.access)(a, Some(first))?;
+        (self.access)(b, Some(second))?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::cell::RefCell;
 
-    fn sort_until_n(n: usize, source: &Vec<i32>) {
-        let mut v = source.clone();
-        let mut v2 = source.clone();
+    fn sort_until_n(n: usize, source: &[i32]) {
+        let mut v = source.to_vec();
+        let mut v2 = source.to_vec();
 
-        let mut bat = BatcherSort::new(
-            0..n,
+        odd_even_mergesort(
+            0..n as u64,
             |x: &i32, y: &i32| x < y,
-            |i: usize, w: Option<&i32>| match w {
+            |i: u64, w: Option<&i32>| match w {
                 Some(x) => {
-                    v[i] = *x;
+                    v[i as usize] = *x;
                     None
                 }
-                None => Some(v[i]),
+                None => Some(v[i as usize]),
             },
         );
 
-        bat.sort();
         v2[0..n].sort();
         assert_eq!(v[0..n], v2[0..n]);
     }
@@ -145,23 +956,419 @@ mod tests {
         }
     }
 
+    #[test]
+    fn bitonic_sort_should_work() {
+        let source = vec![8, 1, 3, 4, 6, 7, 1, 2, 3];
+
+        for n in 1..source.len() {
+            let mut v = source.clone();
+            bitonic_sort(
+                0..n as u64,
+                |x: &i32, y: &i32| x < y,
+                |i, w| match w {
+                    Some(x) => {
+                        v[i as usize] = *x;
+                        None
+                    }
+                    None => Some(v[i as usize]),
+                },
+            );
+            let mut sorted = source[..n].to_vec();
+            sorted.sort();
+            assert_eq!(v[..n], sorted[..]);
+        }
+    }
+
+    #[test]
+    fn networks_sort_all_zero_one_inputs() {
+        // by the 0-1 principle, a network sorting all 0-1 inputs sorts every input
+        for &network in &[Network::OddEvenMerge, Network::Bitonic] {
+            for n in 1..=10u64 {
+                for bits in 0..1u32 << n {
+                    let mut v = (0..n).map(|i| bits >> i & 1).collect::<Vec<_>>();
+                    network
+                        .try_for_each_layer(n, |layer| {
+                            for &(i, j) in layer {
+                                if v[i as usize] > v[j as usize] {
+                                    v.swap(i as usize, j as usize);
+                                }
+                            }
+                            Ok::<_, ()>(())
+                        })
+                        .unwrap();
+                    assert!(v.windows(2).all(|w| w[0] <= w[1]), "{:?} {}", network, n);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn layers_touch_distinct_elements() {
+        for &network in &[Network::OddEvenMerge, Network::Bitonic] {
+            let n = 37;
+            network
+                .try_for_each_layer(n, |layer| {
+                    let mut touched = vec![false; n as usize];
+                    for &(i, j) in layer {
+                        assert!(i < j && j < n);
+                        assert!(!touched[i as usize] && !touched[j as usize]);
+                        touched[i as usize] = true;
+                        touched[j as usize] = true;
+                    }
+                    Ok::<_, ()>(())
+                })
+                .unwrap();
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_sort_matches_sequential() {
+        use std::sync::Mutex;
+
+        let source = (0..100).map(|i| (i * 37 + 11) % 64).collect::<Vec<i32>>();
+        for &network in &[Network::OddEvenMerge, Network::Bitonic] {
+            let v = Mutex::new(source.clone());
+            try_par_sort_with(
+                network,
+                0..source.len() as u64,
+                |x: &i32, y: &i32| x < y,
+                |i| Ok::<_, ()>(v.lock().unwrap()[i as usize]),
+                |i, x, j, y| {
+                    let mut v = v.lock().unwrap();
+                    v[i as usize] = *x;
+                    v[j as usize] = *y;
+                    Ok(())
+                },
+            )
+            .unwrap();
+
+            let mut sorted = source.clone();
+            sorted.sort();
+            assert_eq!(v.into_inner().unwrap(), sorted);
+        }
+    }
+
+    fn melbourne_shuffle(n: u64, seed: u8) -> Vec<u64> {
+        let shuffle = MelbourneShuffle::new(n);
+        let mut v = (0..n).collect::<Vec<u64>>();
+        let mut scratch = vec![None; shuffle.scratch_len() as usize];
+        let mut state = seed as u64;
+        let permutation = shuffle.random_permutation(|buf| {
+            for b in buf.iter_mut() {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                *b = (state >> 56) as u8;
+            }
+        });
+        shuffle.shuffle(
+            &permutation,
+            |i, w| match w {
+                Some(x) => {
+                    v[i as usize] = *x;
+                    None
+                }
+                None => Some(v[i as usize]),
+            },
+            |i, w| match w {
+                Some(x) => {
+                    scratch[i as usize] = *x;
+                    None
+                }
+                None => Some(scratch[i as usize]),
+            },
+        );
+        for (i, &p) in permutation.iter().enumerate() {
+            assert_eq!(v[p as usize], i as u64);
+        }
+        v
+    }
+
+    #[test]
+    fn melbourne_shuffle_applies_permutation() {
+        for &n in &[1, 2, 15, 16, 17, 100, 1000] {
+            melbourne_shuffle(n, 1);
+        }
+        assert_ne!(melbourne_shuffle(100, 1), melbourne_shuffle(100, 2));
+    }
+
+    #[test]
+    fn melbourne_shuffle_accesses_depend_on_length() {
+        let trace = |seed: u8| {
+            let n = 300;
+            let shuffle = MelbourneShuffle::new(n);
+            let mut state = seed;
+            let permutation = shuffle.random_permutation(|buf| {
+                for b in buf.iter_mut() {
+                    state = state.wrapping_mul(31).wrapping_add(17);
+                    *b = state;
+                }
+            });
+            let trace = RefCell::new(Vec::new());
+            let mut scratch = vec![None; shuffle.scratch_len() as usize];
+            shuffle.shuffle(
+                &permutation,
+                |i, w: Option<&u64>| {
+                    trace.borrow_mut().push((false, i, w.is_some()));
+                    Some(i)
+                },
+                |i, w| {
+                    trace.borrow_mut().push((true, i, w.is_some()));
+                    match w {
+                        Some(x) => {
+                            scratch[i as usize] = *x;
+                            None
+                        }
+                        None => Some(scratch[i as usize]),
+                    }
+                },
+            );
+            trace.into_inner()
+        };
+        assert_eq!(trace(1), trace(2));
+    }
+
+    #[test]
+    fn comparators_write_both_elements() {
+        let trace = |source: &[i32]| {
+            let mut v = source.to_vec();
+            let mut trace = Vec::new();
+            odd_even_mergesort(
+                0..v.len() as u64,
+                |x: &i32, y: &i32| x < y,
+                |i, w| {
+                    trace.push((i, w.is_some()));
+                    match w {
+                        Some(x) => {
+                            v[i as usize] = *x;
+                            None
+                        }
+                        None => Some(v[i as usize]),
+                    }
+                },
+            );
+            trace
+        };
+        assert_eq!(trace(&[1, 2, 3, 4, 5]), trace(&[5, 4, 3, 2, 1]));
+    }
+
+    #[test]
+    fn sort_in_chunks_matches_std_sort() {
+        let mut seed = 7u32;
+        for &network in &[Network::OddEvenMerge, Network::Bitonic] {
+            for n in 0..40 {
+                for &memory in &[2, 3, 4, 7, 16, 100] {
+                    let mut v = (0..n)
+                        .map(|_| {
+                            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                            seed >> 24
+                        })
+                        .collect::<Vec<u32>>();
+                    let mut expected = v.clone();
+                    expected.sort();
+                    sort_in_chunks(
+                        network,
+                        0..n,
+                        memory,
+                        |x, y| x < y,
+                        |i, w| match w {
+                            Some(x) => {
+                                v[i as usize] = *x;
+                                None
+                            }
+                            None => Some(v[i as usize]),
+                        },
+                    );
+                    assert_eq!(v, expected, "{:?}, n = {}, memory = {}", network, n, memory);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sort_in_chunks_accesses_depend_on_length() {
+        let trace = |source: &[u32]| {
+            let mut v = source.to_vec();
+            let mut trace = Vec::new();
+            sort_in_chunks(
+                Network::OddEvenMerge,
+                0..v.len() as u64,
+                6,
+                |x, y| x < y,
+                |i, w: Option<&u32>| {
+                    trace.push((i, w.is_some()));
+                    match w {
+                        Some(x) => {
+                            v[i as usize] = *x;
+                            None
+                        }
+                        None => Some(v[i as usize]),
+                    }
+                },
+            );
+            trace
+        };
+        let sorted = (0..20).collect::<Vec<u32>>();
+        let reversed = (0..20).rev().collect::<Vec<u32>>();
+        assert_eq!(trace(&sorted), trace(&reversed));
+    }
+
+    #[test]
+    fn sort_subrange() {
+        for &network in &[Network::OddEvenMerge, Network::Bitonic] {
+            let mut v = vec![9, 8, 7, 6, 5, 4, 3, 2, 1, 0];
+            sort_with(
+                network,
+                3..8,
+                |x: &i32, y: &i32| x < y,
+                |i, w| {
+                    assert!((3..8).contains(&i));
+                    match w {
+                        Some(x) => {
+                            v[i as usize] = *x;
+                            None
+                        }
+                        None => Some(v[i as usize]),
+                    }
+                },
+            );
+            assert_eq!(v, vec![9, 8, 7, 2, 3, 4, 5, 6, 1, 0]);
+        }
+    }
+
+    #[test]
+    fn stable_sort_keeps_order_of_ties() {
+        let mut seed = 11u32;
+        for n in 0..40 {
+            let mut v = (0..n)
+                .map(|i| {
+                    seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    (seed >> 29, i)
+                })
+                .collect::<Vec<(u32, u32)>>();
+            let mut expected = v.clone();
+            expected.sort_by_key(|&(k, _)| k);
+            stable_sort_slice_by_key(&mut v, |&(k, _)| k);
+            assert_eq!(v, expected);
+        }
+
+        // a subrange of an external array, with the bitonic network
+        let mut v = vec![(9, 0), (1, 1), (0, 2), (1, 3), (0, 4), (9, 5)];
+        stable_sort_with(
+            Network::Bitonic,
+            1..5,
+            |x: &(u32, u32), y: &(u32, u32)| x.0 < y.0,
+            |i, w| match w {
+                Some(x) => {
+                    v[i as usize] = *x;
+                    None
+                }
+                None => Some(v[i as usize]),
+            },
+        );
+        assert_eq!(v, vec![(9, 0), (0, 2), (0, 4), (1, 1), (1, 3), (9, 5)]);
+    }
+
+    fn compact_vec(v: &mut [u32], keep: impl Fn(&u32) -> bool) -> u64 {
+        compact(0..v.len() as u64, keep, |i, w| match w {
+            Some(x) => {
+                v[i as usize] = *x;
+                None
+            }
+            None => Some(v[i as usize]),
+        })
+    }
+
+    #[test]
+    fn compact_every_subset() {
+        for n in 0..=10u32 {
+            for mask in 0..1u32 << n {
+                let mut v = (0..n).collect::<Vec<_>>();
+                let expected = v
+                    .iter()
+                    .cloned()
+                    .filter(|i| mask >> i & 1 == 1)
+                    .collect::<Vec<_>>();
+                let kept = compact_vec(&mut v, |i| mask >> i & 1 == 1);
+                assert_eq!(
+                    &v[..kept as usize],
+                    &expected[..],
+                    "n = {}, mask = {:b}",
+                    n,
+                    mask
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn compact_accesses_depend_on_length() {
+        let trace = |mask: u32| {
+            let trace = RefCell::new(Vec::new());
+            let mut v = (0..20).collect::<Vec<u32>>();
+            try_compact(
+                2..18,
+                |i| mask >> i & 1 == 1,
+                |i, w: Option<&u32>| {
+                    trace.borrow_mut().push((i, w.is_some()));
+                    Ok::<_, ()>(match w {
+                        Some(x) => {
+                            v[i as usize] = *x;
+                            None
+                        }
+                        None => Some(v[i as usize]),
+                    })
+                },
+            )
+            .unwrap();
+            assert!(trace.borrow().iter().all(|&(i, _)| (2..18).contains(&i)));
+            trace.into_inner()
+        };
+        assert_eq!(trace(0), trace(0b1010_0110_0101_1100));
+        assert_eq!(trace(0), trace(u32::MAX));
+    }
+
     #[test]
     fn sort_struct() {
         let mut v = vec![(0, 1), (1, 3), (4, 1), (4, 2), (3, 9)];
 
-        let mut bat = BatcherSort::new(
-            0..v.len(),
+        odd_even_mergesort(
+            0..v.len() as u64,
             |x: &(i32, i32), y| (x.0 < y.0) || (x.0 == y.0 && x.1 < y.1),
             |i, w| match w {
                 Some(x) => {
-                    v[i] = *x;
+                    v[i as usize] = *x;
                     None
                 }
-                None => Some(v[i]),
+                None => Some(v[i as usize]),
             },
         );
 
-        bat.sort();
         assert_eq!(v, vec![(0, 1), (1, 3), (3, 9), (4, 1), (4, 2)])
     }
+
+    #[test]
+    fn sort_stops_at_first_error() {
+        let mut v = [3, 2, 1, 0];
+        let mut reads = 0;
+
+        let result = try_odd_even_mergesort(
+            0..v.len() as u64,
+            |x: &i32, y: &i32| x < y,
+            |i, w| match w {
+                Some(x) => {
+                    v[i as usize] = *x;
+                    Ok(None)
+                }
+                None if reads == 2 => Err("read failed"),
+                None => {
+                    reads += 1;
+                    Ok(Some(v[i as usize]))
+                }
+            },
+        );
+
+        assert_eq!(result, Err("read failed"));
+    }
 }
@@ -0,0 +1,397 @@
+// Copyright 2020 ADVANCA PTE. LTD.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Large objects striped across the blocks of an ORAM
+//!
+//! Objects are split into chunks of one block each, kept in a pool ORAM shared by
+//! all objects. A directory ORAM maps every object to the list of its chunks.
+//! Every operation reads the directory entry, accesses exactly `max_chunks`
+//! chunks, padding with dummy reads, and writes the entry back. The host sees
+//! the same number of accesses for any object size, and as the chunk positions
+//! are themselves hidden by the ORAM, the allocator is free to hand out any
+//! free chunk.
+
+#[cfg(feature = "sgx")]
+use sgx_tstd::{self as std, prelude::v1::*};
+
+use crate::{deserialize, serialize};
+use crate::{Data, OramError, Result, SqrtOram};
+
+/// List of chunks of a stored object, `None` if no object is stored
+type Entry = Option<Vec<u64>>;
+
+enum Op {
+    Get,
+    Put(Data),
+    Remove,
+}
+
+/// An ORAM for objects larger than one block
+///
+/// # Examples
+///
+/// ```
+/// use oram::StripedOram;
+///
+/// // 4 objects of up to 8 chunks of 64 bytes, sharing a pool of 16 chunks
+/// let mut oram = StripedOram::new(4, 64, 8, 16).unwrap();
+/// oram.put(1, vec![7; 300]).unwrap();
+/// assert_eq!(oram.get(1).unwrap(), Some(vec![7; 300]));
+/// assert_eq!(oram.get(2).unwrap(), None);
+/// ```
+pub struct StripedOram {
+    /// Chunk lists of the objects
+    directory: SqrtOram,
+    /// Pool of chunks
+    chunks: SqrtOram,
+    /// Number of chunks accessed by every operation
+    max_chunks: u64,
+    /// Chunks not used by any object
+    free: Vec<u64>,
+}
+
+impl StripedOram {
+    /// Create an in-memory ORAM of `n` objects
+    ///
+    /// - `chunk_size`: size of each chunk in bytes
+    /// - `max_chunks`: maximum number of chunks of an object
+    /// - `num_chunks`: number of chunks shared by all objects
+    pub fn new(n: u64, chunk_size: usize, max_chunks: u64, num_chunks: u64) -> Result<Self> {
+        let directory = SqrtOram::new(n, Self::directory_block_size(max_chunks))?;
+        let chunks = SqrtOram::new(num_chunks, chunk_size)?;
+        Self::create(directory, chunks, max_chunks)
+    }
+
+    /// Create an empty store in `directory` and `chunks`, e.g. ORAMs made by `OramBuilder`
+    ///
+    /// Every block of `directory` is cleared. Its block size has to be at least
+    /// `directory_block_size(max_chunks)`.
+    pub fn create(directory: SqrtOram, chunks: SqrtOram, max_chunks: u64) -> Result<Self> {
+        let mut oram = Self::with_free_list(directory, chunks, max_chunks)?;
+        let empty = serialize(&Entry::None).expect("serialize entry");
+        for k in 0..oram.directory.num_blocks() {
+            oram.directory.put(k, empty.clone())?;
+        }
+        oram.free = (0..oram.chunks.num_blocks()).rev().collect();
+        Ok(oram)
+    }
+
+    /// Use the store previously created in `directory` and `chunks`
+    ///
+    /// The whole directory is read to find the free chunks.
+    pub fn open(directory: SqrtOram, chunks: SqrtOram, max_chunks: u64) -> Result<Self> {
+        let mut oram = Self::with_free_list(directory, chunks, max_chunks)?;
+        let mut used = vec![false; oram.chunks.num_blocks() as usize];
+        for k in 0..oram.directory.num_blocks() {
+            for chunk in oram.read_entry(k)?.unwrap_or_default() {
+                match used.get_mut(chunk as usize) {
+                    Some(used) if !*used => *used = true,
+                    // out of the pool or shared by two objects
+                    _ => return Err(OramError::Integrity(None)),
+                }
+            }
+        }
+        oram.free = (0..oram.chunks.num_blocks())
+            .rev()
+            .filter(|&chunk| !used[chunk as usize])
+            .collect();
+        Ok(oram)
+    }
+
+    fn with_free_list(directory: SqrtOram, chunks: SqrtOram, max_chunks: u64) -> Result<Self> {
+        if max_chunks == 0 {
+            return Err(OramError::InvalidConfig(
+                "maximum number of chunks must be positive".into(),
+            ));
+        }
+        if directory.block_size() < Self::directory_block_size(max_chunks) {
+            return Err(OramError::InvalidConfig(
+                "directory block size is too small for a full chunk list".into(),
+            ));
+        }
+        Ok(StripedOram {
+            directory,
+            chunks,
+            max_chunks,
+            free: Vec::new(),
+        })
+    }
+
+    /// The directory block size needed for objects of `max_chunks` chunks
+    pub fn directory_block_size(max_chunks: u64) -> usize {
+        // bincode encodes `Option` in one byte and prefixes `Vec` with a u64 length
+        1 + 8 + 8 * max_chunks as usize
+    }
+
+    /// Number of objects; valid keys are `0..num_objects()`
+    pub fn num_objects(&self) -> u64 {
+        self.directory.num_blocks()
+    }
+
+    /// Maximum length of an object in bytes
+    pub fn max_object_size(&self) -> usize {
+        self.max_chunks as usize * self.chunks.block_size()
+    }
+
+    /// Number of chunks not used by any object
+    pub fn free_chunks(&self) -> u64 {
+        self.free.len() as u64
+    }
+
+    /// Store object `v` at key `k`
+    ///
+    /// # Errors
+    ///
+    /// - `OramError::Capacity` when `v` is longer than `max_object_size()`
+    /// - `OramError::Full` when there are not enough free chunks. The chunks of
+    ///   an object being replaced are only freed by the put, so they do not count.
+    ///   The chunks are still accessed, so a failed put looks like any other
+    ///   operation to the host.
+    pub fn put(&mut self, k: u64, v: Data) -> Result<()> {
+        if v.len() > self.max_object_size() {
+            return Err(OramError::Capacity {
+                len: v.len(),
+                block_size: self.max_object_size(),
+            });
+        }
+        self.access(k, Op::Put(v)).map(|_| ())
+    }
+
+    /// Read the object at key `k`
+    pub fn get(&mut self, k: u64) -> Result<Option<Data>> {
+        self.access(k, Op::Get)
+    }
+
+    /// Remove the object at key `k`, returning it
+    pub fn remove(&mut self, k: u64) -> Result<Option<Data>> {
+        self.access(k, Op::Remove)
+    }
+
+    /// Unwrap the directory and chunk ORAMs
+    pub fn into_inner(self) -> (SqrtOram, SqrtOram) {
+        (self.directory, self.chunks)
+    }
+
+    /// Run `op` on `k` with one directory read, `max_chunks` chunk accesses
+    /// and one directory write
+    ///
+    /// A put writes to free chunks and the free list is only updated once the
+    /// directory write succeeded, so a failed operation neither corrupts the
+    /// old object, loses chunks nor hands out chunks still in use.
+    fn access(&mut self, k: u64, op: Op) -> Result<Option<Data>> {
+        let entry = self.read_entry(k)?;
+        let old_chunks = entry.clone().unwrap_or_default();
+        let max_chunks = self.max_chunks as usize;
+        // chunks taken from the end of the free list, and chunks given back
+        let mut taken = 0;
+        let mut released = Vec::new();
+
+        let (new_entry, result) = match op {
+            Op::Put(v) => {
+                let pieces: Vec<&[u8]> = v.chunks(self.chunks.block_size()).collect();
+                if pieces.len() > self.free.len() {
+                    for _ in 0..max_chunks {
+                        self.chunks.get(0)?;
+                    }
+                    (entry, Err(OramError::Full))
+                } else {
+                    // fresh chunks, so the old object stays intact until the
+                    // new entry is written
+                    taken = pieces.len();
+                    released = old_chunks;
+                    let chunks: Vec<u64> = self.free.iter().rev().take(taken).copied().collect();
+                    for (&chunk, piece) in chunks.iter().zip(&pieces) {
+                        self.chunks.put(chunk, piece.to_vec())?;
+                    }
+                    for _ in pieces.len()..max_chunks {
+                        self.chunks.get(0)?;
+                    }
+                    (Some(chunks), Ok(None))
+                }
+            }
+            Op::Get | Op::Remove => {
+                let mut data = entry.as_ref().map(|_| Data::new());
+                for i in 0..max_chunks {
+                    let chunk = old_chunks.get(i).copied();
+                    let piece = self.chunks.get(chunk.unwrap_or(0))?;
+                    if let (Some(data), Some(_)) = (&mut data, chunk) {
+                        data.extend(piece.ok_or(OramError::Integrity(None))?);
+                    }
+                }
+                if let Op::Remove = op {
+                    released = old_chunks;
+                    (None, Ok(data))
+                } else {
+                    (entry, Ok(data))
+                }
+            }
+        };
+
+        self.write_entry(k, &new_entry)?;
+        let kept = self.free.len() - taken;
+        self.free.truncate(kept);
+        self.free.extend(released);
+        result
+    }
+
+    fn read_entry(&mut self, k: u64) -> Result<Entry> {
+        match self.directory.get(k)? {
+            Some(data) => deserialize(&data).map_err(|e| OramError::Serialization(e.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    fn write_entry(&mut self, k: u64, entry: &Entry) -> Result<()> {
+        self.directory
+            .put(k, serialize(entry).expect("serialize entry"))
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::OramBuilder;
+
+    fn object(len: usize) -> Data {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn put_and_get_objects_of_any_size() {
+        let mut oram = StripedOram::new(8, 16, 4, 32).unwrap();
+
+        for k in 0..8 {
+            oram.put(k, object(k as usize * 9)).unwrap();
+        }
+        for k in 0..8 {
+            assert_eq!(oram.get(k).unwrap(), Some(object(k as usize * 9)));
+        }
+        // 0, 1, 2, 2, 3, 3, 4 and 4 chunks
+        assert_eq!(oram.free_chunks(), 32 - 19);
+    }
+
+    #[test]
+    fn overwrite_and_remove_release_chunks() {
+        let mut oram = StripedOram::new(4, 16, 4, 8).unwrap();
+        assert_eq!(oram.get(0).unwrap(), None);
+
+        oram.put(0, object(64)).unwrap();
+        assert_eq!(oram.free_chunks(), 4);
+        oram.put(0, object(20)).unwrap();
+        assert_eq!(oram.free_chunks(), 6);
+        assert_eq!(oram.get(0).unwrap(), Some(object(20)));
+
+        oram.put(1, vec![]).unwrap();
+        assert_eq!(oram.get(1).unwrap(), Some(vec![]));
+
+        assert_eq!(oram.remove(0).unwrap(), Some(object(20)));
+        assert_eq!(oram.get(0).unwrap(), None);
+        assert_eq!(oram.free_chunks(), 8);
+    }
+
+    #[test]
+    fn reject_large_object_and_full_pool() {
+        let mut oram = StripedOram::new(4, 16, 4, 6).unwrap();
+
+        assert!(matches!(
+            oram.put(0, object(65)),
+            Err(OramError::Capacity { .. })
+        ));
+        oram.put(0, object(64)).unwrap();
+        assert_eq!(oram.put(1, object(48)), Err(OramError::Full));
+        assert_eq!(oram.get(1).unwrap(), None);
+        assert_eq!(oram.get(0).unwrap(), Some(object(64)));
+    }
+
+    #[test]
+    fn failed_put_keeps_free_chunks() {
+        // a directory too small for entries of more than one chunk
+        let mut oram = StripedOram {
+            directory: SqrtOram::new(4, StripedOram::directory_block_size(1)).unwrap(),
+            chunks: SqrtOram::new(8, 16).unwrap(),
+            max_chunks: 4,
+            free: (0..8).rev().collect(),
+        };
+        for k in 0..4 {
+            oram.write_entry(k, &None).unwrap();
+        }
+        oram.put(0, object(16)).unwrap();
+        assert_eq!(oram.free_chunks(), 7);
+
+        assert!(matches!(
+            oram.put(1, vec![0xff; 40]),
+            Err(OramError::Capacity { .. })
+        ));
+        assert_eq!(oram.free_chunks(), 7);
+        assert!(matches!(
+            oram.put(0, vec![0xff; 40]),
+            Err(OramError::Capacity { .. })
+        ));
+        assert_eq!(oram.free_chunks(), 7);
+        assert_eq!(oram.get(0).unwrap(), Some(object(16)));
+
+        oram.put(1, object(16)).unwrap();
+        assert_eq!(oram.get(0).unwrap(), Some(object(16)));
+        assert_eq!(oram.get(1).unwrap(), Some(object(16)));
+        assert_eq!(oram.free_chunks(), 6);
+    }
+
+    #[test]
+    fn reopen_restores_free_chunks() {
+        let mut oram = StripedOram::new(4, 16, 4, 8).unwrap();
+        oram.put(2, object(40)).unwrap();
+
+        let (directory, chunks) = oram.into_inner();
+        let mut oram = StripedOram::open(directory, chunks, 4).unwrap();
+        assert_eq!(oram.free_chunks(), 5);
+        oram.put(3, object(80)).unwrap_err();
+        oram.put(3, object(64)).unwrap();
+        assert_eq!(oram.get(2).unwrap(), Some(object(40)));
+        assert_eq!(oram.get(3).unwrap(), Some(object(64)));
+    }
+
+    #[test]
+    fn constant_number_of_accesses() {
+        // with the shelters as large as the ORAMs, `count` tracks accesses modulo n
+        let (n, num_chunks, max_chunks) = (4, 8, 3);
+        let directory = OramBuilder::new(n, StripedOram::directory_block_size(max_chunks))
+            .shelter_size(n)
+            .build()
+            .unwrap();
+        let chunks = OramBuilder::new(num_chunks, 16)
+            .shelter_size(num_chunks)
+            .build()
+            .unwrap();
+        let mut oram = StripedOram::create(directory, chunks, max_chunks).unwrap();
+        oram.put(0, object(40)).unwrap();
+        oram.put(1, object(40)).unwrap();
+
+        for op in 0..6 {
+            let before = (oram.directory.count, oram.chunks.count);
+            let _ = match op {
+                0 => oram.get(0),
+                1 => oram.get(2),
+                2 => oram.put(0, object(1)).map(|_| None),
+                3 => oram.put(2, object(48)).map(|_| None),
+                4 => oram.remove(1),
+                _ => oram.put(3, object(48)).map(|_| None),
+            };
+            assert_eq!((oram.directory.count + n - before.0) % n, 2);
+            assert_eq!(
+                (oram.chunks.count + num_chunks - before.1) % num_chunks,
+                max_chunks
+            );
+        }
+    }
+}
@@ -0,0 +1,486 @@
+// Copyright 2020 ADVANCA PTE. LTD.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Traces of the storage accesses the host sees, for auditing obliviousness
+//!
+//! A `Recorder` passed to `OramBuilder::trace()` records every read, write
+//! and delete reaching the backend, after sealing: the storage key, the length
+//! of the value and a hash of it. A `Trace` can then be compared with another one,
+//! summarized, or replayed to check that the backend returned what was written.
+//!
+//! ```
+//! use oram::trace::Recorder;
+//! use oram::OramBuilder;
+//!
+//! let recorder = Recorder::in_memory();
+//! let mut oram = OramBuilder::new(16, 8).trace(&recorder).build().unwrap();
+//! oram.put(3, vec![1, 2, 3]).unwrap();
+//!
+//! let trace = recorder.trace().unwrap();
+//! assert!(trace.replay().mismatches.is_empty());
+//! println!("{} accesses", trace.summary().gets + trace.summary().puts);
+//! ```
+
+#[cfg(feature = "sgx")]
+use sgx_tstd::{self as std, prelude::v1::*};
+
+use crate::crypto::short_hash;
+use crate::vec::Vec;
+use crate::{OramError, Result, RwLock};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+#[cfg(feature = "std")]
+use std::fs::File;
+#[cfg(feature = "std")]
+use std::io::{BufRead, BufReader, BufWriter, Write};
+#[cfg(feature = "std")]
+use std::path::{Path, PathBuf};
+
+/// A storage operation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Get,
+    Put,
+    Delete,
+}
+
+/// What the host sees of a value
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ValueDigest {
+    pub len: u64,
+    /// The 8-byte BLAKE2b hash of the value
+    pub hash: u64,
+}
+
+impl ValueDigest {
+    fn new(value: &[u8]) -> Self {
+        ValueDigest {
+            len: value.len() as u64,
+            hash: short_hash(value),
+        }
+    }
+}
+
+/// One access to the backend
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    pub op: Op,
+    pub key: Vec<u8>,
+    /// The value written or read, `None` for a read of a missing key or a delete
+    pub value: Option<ValueDigest>,
+}
+
+impl Event {
+    pub(crate) fn new(op: Op, key: &[u8], value: Option<&[u8]>) -> Self {
+        Event {
+            op,
+            key: key.to_vec(),
+            value: value.map(ValueDigest::new),
+        }
+    }
+}
+
+enum Sink {
+    Memory(Vec<Event>),
+    #[cfg(feature = "std")]
+    File(BufWriter<File>, PathBuf),
+}
+
+/// Where the accesses of a store are recorded
+///
+/// Clones share the same trace. Parallel sorts record from several threads,
+/// so the order of the accesses within one layer of a sorting network varies.
+#[derive(Clone)]
+pub struct Recorder {
+    sink: Arc<RwLock<Sink>>,
+}
+
+impl Recorder {
+    /// Record into memory
+    pub fn in_memory() -> Self {
+        Self::with_sink(Sink::Memory(Vec::new()))
+    }
+
+    /// Record into the file at `path`, one line per access, see `Trace::load()`
+    #[cfg(feature = "std")]
+    pub fn to_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::create(&path).map_err(|e| OramError::Storage(e.to_string()))?;
+        Ok(Self::with_sink(Sink::File(BufWriter::new(file), path)))
+    }
+
+    fn with_sink(sink: Sink) -> Self {
+        Recorder {
+            sink: Arc::new(RwLock::new(sink)),
+        }
+    }
+
+    /// The accesses recorded so far
+    pub fn trace(&self) -> Result<Trace> {
+        self.flush()?;
+        let sink = self.sink.read().expect("trace lock");
+        match &*sink {
+            Sink::Memory(events) => Ok(Trace::from(events.clone())),
+            #[cfg(feature = "std")]
+            Sink::File(_, path) => Trace::load(path),
+        }
+    }
+
+    pub(crate) fn record(&self, event: Event) -> Result<()> {
+        let mut sink = self.sink.write().expect("trace lock");
+        match &mut *sink {
+            Sink::Memory(events) => {
+                events.push(event);
+                Ok(())
+            }
+            #[cfg(feature = "std")]
+            Sink::File(writer, _) => {
+                write_event(writer, &event).map_err(|e| OramError::Storage(e.to_string()))
+            }
+        }
+    }
+
+    pub(crate) fn flush(&self) -> Result<()> {
+        let mut sink = self.sink.write().expect("trace lock");
+        match &mut *sink {
+            Sink::Memory(_) => Ok(()),
+            #[cfg(feature = "std")]
+            Sink::File(writer, _) => writer
+                .flush()
+                .map_err(|e| OramError::Storage(e.to_string())),
+        }
+    }
+}
+
+/// A recorded sequence of accesses
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Trace {
+    events: Vec<Event>,
+}
+
+impl From<Vec<Event>> for Trace {
+    fn from(events: Vec<Event>) -> Self {
+        Trace { events }
+    }
+}
+
+/// The first access at which two traces differ
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceDiff {
+    pub index: usize,
+    /// The access of the first trace, `None` if it ended
+    pub left: Option<Event>,
+    /// The access of the second trace, `None` if it ended
+    pub right: Option<Event>,
+}
+
+/// Accesses of one key
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeyAccesses {
+    pub gets: u64,
+    pub puts: u64,
+    pub deletes: u64,
+}
+
+/// Counts of the accesses of a trace
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub gets: u64,
+    pub puts: u64,
+    pub deletes: u64,
+    /// Reads of missing keys
+    pub misses: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Writes of the value the key already held, which the host tells apart
+    /// from writes of new values
+    pub unchanged_puts: u64,
+    pub keys: BTreeMap<Vec<u8>, KeyAccesses>,
+}
+
+impl Summary {
+    /// The number of keys accessed a given number of times
+    pub fn histogram(&self) -> BTreeMap<u64, u64> {
+        let mut histogram = BTreeMap::new();
+        for accesses in self.keys.values() {
+            let total = accesses.gets + accesses.puts + accesses.deletes;
+            *histogram.entry(total).or_insert(0) += 1;
+        }
+        histogram
+    }
+}
+
+/// The store a trace leaves behind, and the reads that do not match it
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Replay {
+    /// The last value of every key accessed
+    pub state: BTreeMap<Vec<u8>, Option<ValueDigest>>,
+    /// Indices of the reads returning something other than the last value
+    /// written to their key
+    pub mismatches: Vec<usize>,
+}
+
+impl Trace {
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Compare with `other` in what the host can tell apart: the operations,
+    /// the keys and the lengths of the values
+    ///
+    /// The hashes are left out, as sealed values differ on every write.
+    pub fn diff(&self, other: &Trace) -> Option<TraceDiff> {
+        let pattern = |e: &Event| (e.op, e.key.clone(), e.value.map(|v| v.len));
+        let len = self.len().max(other.len());
+        (0..len)
+            .find(|&i| self.events.get(i).map(pattern) != other.events.get(i).map(pattern))
+            .map(|index| TraceDiff {
+                index,
+                left: self.events.get(index).cloned(),
+                right: other.events.get(index).cloned(),
+            })
+    }
+
+    pub fn summary(&self) -> Summary {
+        let mut summary = Summary::default();
+        let mut last = BTreeMap::new();
+        for event in &self.events {
+            let accesses = summary.keys.entry(event.key.clone()).or_default();
+            let len = event.value.map_or(0, |v| v.len);
+            match event.op {
+                Op::Get => {
+                    accesses.gets += 1;
+                    summary.gets += 1;
+                    summary.misses += event.value.is_none() as u64;
+                    summary.bytes_read += len;
+                }
+                Op::Put => {
+                    accesses.puts += 1;
+                    summary.puts += 1;
+                    summary.bytes_written += len;
+                    if last.get(&event.key) == Some(&event.value) {
+                        summary.unchanged_puts += 1;
+                    }
+                }
+                Op::Delete => {
+                    accesses.deletes += 1;
+                    summary.deletes += 1;
+                }
+            }
+            last.insert(event.key.clone(), event.value);
+        }
+        summary
+    }
+
+    /// Run the trace against a model of the store, checking that every read
+    /// returns the last value written to its key
+    ///
+    /// A key read before any write in the trace may hold anything, so its
+    /// first read is taken as its value.
+    pub fn replay(&self) -> Replay {
+        let mut replay = Replay::default();
+        for (i, event) in self.events.iter().enumerate() {
+            match (event.op, replay.state.get(&event.key)) {
+                (Op::Get, Some(value)) if *value != event.value => replay.mismatches.push(i),
+                (Op::Get, Some(_)) => {}
+                _ => {
+                    replay.state.insert(event.key.clone(), event.value);
+                }
+            }
+        }
+        replay
+    }
+
+    /// Read a trace written by `Recorder::to_file()` or `save()`
+    #[cfg(feature = "std")]
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Trace> {
+        let file = File::open(path).map_err(|e| OramError::Storage(e.to_string()))?;
+        let mut events = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|e| OramError::Storage(e.to_string()))?;
+            let event = parse_event(&line)
+                .ok_or_else(|| OramError::Serialization("malformed trace line".into()))?;
+            events.push(event);
+        }
+        Ok(Trace { events })
+    }
+
+    #[cfg(feature = "std")]
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let io = |e: std::io::Error| OramError::Storage(e.to_string());
+        let mut writer = BufWriter::new(File::create(path).map_err(io)?);
+        for event in &self.events {
+            write_event(&mut writer, event).map_err(io)?;
+        }
+        writer.flush().map_err(io)
+    }
+}
+
+/// Write `event` as a line of `get`, `put` or `delete`, the key in hex, then
+/// the length and hash of the value, or `-` for a missing value
+#[cfg(feature = "std")]
+fn write_event<W: Write>(writer: &mut W, event: &Event) -> std::io::Result<()> {
+    let op = match event.op {
+        Op::Get => "get",
+        Op::Put => "put",
+        Op::Delete => "delete",
+    };
+    write!(writer, "{} ", op)?;
+    for b in &event.key {
+        write!(writer, "{:02x}", b)?;
+    }
+    match event.value {
+        Some(value) => writeln!(writer, " {} {:016x}", value.len, value.hash),
+        None => writeln!(writer, " -"),
+    }
+}
+
+#[cfg(feature = "std")]
+fn parse_event(line: &str) -> Option<Event> {
+    let fields = line.split(' ').collect::<Vec<_>>();
+    let op = match *fields.first()? {
+        "get" => Op::Get,
+        "put" => Op::Put,
+        "delete" => Op::Delete,
+        _ => return None,
+    };
+    let hex = fields.get(1)?;
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    let key = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    let value = match fields.get(2..)? {
+        ["-"] => None,
+        [len, hash] => Some(ValueDigest {
+            len: len.parse().ok()?,
+            hash: u64::from_str_radix(hash, 16).ok()?,
+        }),
+        _ => return None,
+    };
+    Some(Event { op, key, value })
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{OramBuilder, ShuffleMode};
+
+    fn traced_run(keys: &[u64]) -> Trace {
+        let recorder = Recorder::in_memory();
+        let mut oram = OramBuilder::new(16, 8)
+            .shuffle_mode(ShuffleMode::SortByTag)
+            .encryption_key([1; 32])
+            .encryption(true)
+            .trace(&recorder)
+            .build()
+            .unwrap();
+        for &k in keys {
+            oram.put(k, vec![k as u8]).unwrap();
+        }
+        recorder.trace().unwrap()
+    }
+
+    #[test]
+    fn record_what_the_host_sees() {
+        let trace = traced_run(&[1, 2, 3]);
+        assert!(!trace.is_empty());
+        assert!(trace.replay().mismatches.is_empty());
+
+        // positions are random, but their number does not depend on the keys
+        let other = traced_run(&[7, 7, 0]);
+        assert!(trace.diff(&other).is_some());
+        assert_eq!(trace.len(), other.len());
+        assert_eq!(trace.diff(&trace), None);
+
+        let summary = trace.summary();
+        assert_eq!(summary.gets + summary.puts, trace.len() as u64);
+        assert_eq!(summary.gets, other.summary().gets);
+        assert_eq!(summary.unchanged_puts, 0);
+        let histogram = summary.histogram();
+        assert_eq!(histogram.values().sum::<u64>(), summary.keys.len() as u64);
+    }
+
+    #[test]
+    fn diff_summarize_and_replay() {
+        let event = |op, key: &[u8], value: Option<&[u8]>| Event::new(op, key, value);
+        let trace = Trace::from(vec![
+            event(Op::Get, b"a", None),
+            event(Op::Put, b"a", Some(b"1")),
+            event(Op::Put, b"a", Some(b"1")),
+            event(Op::Get, b"a", Some(b"2")),
+            event(Op::Get, b"b", Some(b"3")),
+            event(Op::Delete, b"b", None),
+            event(Op::Get, b"b", None),
+        ]);
+
+        let summary = trace.summary();
+        assert_eq!((summary.gets, summary.puts, summary.misses), (4, 2, 2));
+        assert_eq!(summary.deletes, 1);
+        assert_eq!((summary.bytes_read, summary.bytes_written), (2, 2));
+        assert_eq!(summary.unchanged_puts, 1);
+        assert_eq!(
+            summary.keys[&b"a"[..]],
+            KeyAccesses {
+                gets: 2,
+                puts: 2,
+                deletes: 0
+            }
+        );
+        assert_eq!(
+            summary.histogram().into_iter().collect::<Vec<_>>(),
+            vec![(3, 1), (4, 1)]
+        );
+
+        let replay = trace.replay();
+        assert_eq!(replay.mismatches, vec![3]);
+        assert_eq!(replay.state[&b"b"[..]], None);
+
+        let mut other = trace.events().to_vec();
+        other[4].key = b"c".to_vec();
+        let diff = trace.diff(&Trace::from(other)).unwrap();
+        assert_eq!(diff.index, 4);
+        let shorter = Trace::from(trace.events()[..2].to_vec());
+        assert_eq!(trace.diff(&shorter).unwrap().right, None);
+    }
+
+    #[test]
+    fn trace_file() {
+        let path = std::env::temp_dir().join("oram_trace_file");
+        let recorder = Recorder::to_file(&path).unwrap();
+        let mut oram = OramBuilder::new(16, 8).trace(&recorder).build().unwrap();
+        oram.put(1, vec![1]).unwrap();
+        assert_eq!(oram.get(1).unwrap(), Some(vec![1]));
+        // shrinking deletes the positions past the new capacity
+        oram.resize(8).unwrap();
+
+        let trace = recorder.trace().unwrap();
+        assert!(trace.events().iter().any(|e| e.op == Op::Get));
+        assert!(trace.events().iter().any(|e| e.op == Op::Delete));
+        trace.save(&path).unwrap();
+        assert_eq!(Trace::load(&path).unwrap(), trace);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,152 @@
+// Copyright 2020 ADVANCA PTE. LTD.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#[cfg(feature = "sgx")]
+use sgx_tstd::{self as std, prelude::v1::*};
+
+use crate::{deserialize, serialize, serialized_size};
+use crate::{DeserializeOwned, OramError, Result, Serialize, SqrtOram};
+use std::marker::PhantomData;
+
+/// An ORAM storing serde values instead of raw bytes
+///
+/// Values are encoded with bincode. Every encoded value has to fit in one block,
+/// so the block size is the maximum encoded size of `V`.
+///
+/// # Examples
+///
+/// ```
+/// use oram::TypedOram;
+///
+/// let max_size = TypedOram::<(u32, String)>::block_size_for(&(0, "x".repeat(16))).unwrap();
+/// let mut oram = TypedOram::new(16, max_size).unwrap();
+/// oram.put(3, &(7, "seven".to_string())).unwrap();
+/// assert_eq!(oram.get(3).unwrap(), Some((7, "seven".to_string())));
+/// ```
+pub struct TypedOram<V> {
+    oram: SqrtOram,
+    phantom: PhantomData<V>,
+}
+
+impl<V> TypedOram<V>
+where
+    V: Serialize + DeserializeOwned,
+{
+    /// Create an in-memory ORAM of `n` values, each encoded in at most `max_size` bytes
+    pub fn new(n: u64, max_size: usize) -> Result<Self> {
+        Ok(Self::from_oram(SqrtOram::new(n, max_size)?))
+    }
+
+    /// Store values in `oram`, e.g. one made by `OramBuilder`
+    ///
+    /// The block size of `oram` bounds the encoded size of values.
+    pub fn from_oram(oram: SqrtOram) -> Self {
+        TypedOram {
+            oram,
+            phantom: PhantomData,
+        }
+    }
+
+    /// The block size needed to store `largest`, the value with the largest encoding
+    pub fn block_size_for(largest: &V) -> Result<usize> {
+        serialized_size(largest)
+            .map(|size| size as usize)
+            .map_err(|e| OramError::Serialization(e.to_string()))
+    }
+
+    /// Store `v` at key `k`
+    ///
+    /// # Errors
+    ///
+    /// `OramError::Capacity` if the encoded value does not fit in a block,
+    /// besides the errors of `SqrtOram::put()`
+    pub fn put(&mut self, k: u64, v: &V) -> Result<()> {
+        let data = serialize(v).map_err(|e| OramError::Serialization(e.to_string()))?;
+        self.oram.put(k, data)
+    }
+
+    /// Read the value at key `k`
+    pub fn get(&mut self, k: u64) -> Result<Option<V>> {
+        match self.oram.get(k)? {
+            Some(data) => deserialize(&data)
+                .map(Some)
+                .map_err(|e| OramError::Serialization(e.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    /// The underlying ORAM
+    pub fn inner(&mut self) -> &mut SqrtOram {
+        &mut self.oram
+    }
+
+    /// Unwrap the underlying ORAM
+    pub fn into_inner(self) -> SqrtOram {
+        self.oram
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Record {
+        id: u64,
+        name: String,
+        scores: Vec<u32>,
+    }
+
+    fn record(id: u64) -> Record {
+        Record {
+            id,
+            name: format!("record {}", id),
+            scores: vec![id as u32; 4],
+        }
+    }
+
+    #[test]
+    fn put_and_get() {
+        let n = 16;
+        let largest = Record {
+            name: "x".repeat(16),
+            ..record(0)
+        };
+        let max_size = TypedOram::block_size_for(&largest).unwrap();
+        let mut oram = TypedOram::new(n, max_size).unwrap();
+
+        for i in 0..n {
+            oram.put(i, &record(i)).unwrap();
+        }
+        for i in 0..n {
+            assert_eq!(oram.get(i).unwrap(), Some(record(i)));
+        }
+    }
+
+    #[test]
+    fn reject_oversized_value() {
+        let max_size = TypedOram::block_size_for(&record(0)).unwrap();
+        let mut oram = TypedOram::new(4, max_size).unwrap();
+
+        let large = Record {
+            name: "x".repeat(100),
+            ..record(1)
+        };
+        match oram.put(1, &large) {
+            Err(OramError::Capacity { block_size, .. }) => assert_eq!(block_size, max_size),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}